@@ -3,17 +3,159 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+const KB: u32 = 1024;
+const MB: u32 = 1024 * KB;
+
+/// Address the flash cache maps its segment at
+const IROM_BASE: u32 = 0x4020_0000;
+
+/// Offset of `irom_seg` in the flash without OTA
+const IROM_OFFSET: u32 = 0x2_0000;
+
+/// Space at the end of the flash reserved for the SDK system parameters and RF calibration
+const SYSTEM_PARAMETERS: u32 = 16 * KB;
+
+/// Offset of the firmware in an rBoot slot, after the rBoot and rBoot config sectors
+const OTA_SLOT_OFFSET: u32 = 0x2000;
+
+/// Size of the header of an rBoot (v2) firmware image, before `irom_seg`
+const OTA_IMAGE_HEADER: u32 = 0x10;
+
+fn feature(name: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
+
+/// Flash size selected with the `flash-*` and `esp8285` features, 4MB by default
+fn flash_size() -> u32 {
+    let sizes = [
+        ("FLASH_512K", 512 * KB),
+        ("FLASH_1M", MB),
+        ("FLASH_2M", 2 * MB),
+        ("FLASH_4M", 4 * MB),
+        // the ESP8285 has 1MB of built-in flash
+        ("ESP8285", MB),
+    ];
+    let selected = sizes
+        .iter()
+        .filter(|(name, _)| feature(name))
+        .collect::<Vec<_>>();
+    match selected.as_slice() {
+        [] => 4 * MB,
+        [(_, size)] => *size,
+        _ => panic!("only one of the flash-* and esp8285 features can be enabled"),
+    }
+}
+
+/// OTA slot selected with the `ota-slot-*` features
+fn ota_slot() -> Option<u32> {
+    match (feature("OTA_SLOT_0"), feature("OTA_SLOT_1")) {
+        (false, false) => None,
+        (true, false) => Some(0),
+        (false, true) => Some(1),
+        (true, true) => panic!("only one of the ota-slot-* features can be enabled"),
+    }
+}
+
+/// Offset in the flash and length of `irom_seg`, and the flash segment mapped by the cache
+fn irom_layout(flash_size: u32, ota_slot: Option<u32>) -> (u32, u32, u32) {
+    let usable = flash_size - SYSTEM_PARAMETERS;
+    let (start, end, segment) = match ota_slot {
+        None => (IROM_OFFSET, usable.min(MB), 0),
+        // each slot in its own MB, mapped by switching the cache segment like rBoot's big flash
+        // support
+        Some(slot) if flash_size >= 2 * MB => (
+            slot * MB + OTA_SLOT_OFFSET + OTA_IMAGE_HEADER,
+            ((slot + 1) * MB).min(usable),
+            slot,
+        ),
+        // both slots in the first MB
+        Some(slot) => {
+            let slot_size = flash_size / 2;
+            (
+                slot * slot_size + OTA_SLOT_OFFSET + OTA_IMAGE_HEADER,
+                ((slot + 1) * slot_size).min(usable),
+                0,
+            )
+        }
+    };
+    (start, end - start, segment)
+}
+
 fn main() {
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
 
     // Put the linker script somewhere the linker can find it
     File::create(out.join("link.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(include_bytes!("link.x"))
         .unwrap();
+
+    // Default memory regions for the selected flash layout
+    let flash_size = flash_size();
+    let ota_slot = ota_slot();
+    let (irom_offset, irom_len, segment) = irom_layout(flash_size, ota_slot);
+    let mut layout = File::create(out.join("memory-default.x")).unwrap();
+    writeln!(
+        layout,
+        "/* {}KB flash{} */",
+        flash_size / KB,
+        ota_slot.map_or(String::new(), |slot| format!(", OTA slot {}", slot))
+    )
+    .unwrap();
+    writeln!(
+        layout,
+        "MEMORY
+{{
+      /* All .data/.bss/heap are in this segment. Reserve 1KB for old boot or ROM boot */
+      dram_seg :     org = 0x3FFE8000, len = 0x14000
+
+      vectors_seg :  org = 0x40100000, len = 0x100
+      iram_seg :     org = 0x40100100, len = 0x8000 - 0x0100
+      irom_seg :     org = {:#010X}, len = {:#X}
+}}
+
+/* MB of the flash mapped at 0x40200000 by the cache, irom_seg must lie in this segment */
+_flash_cache_segment = {};",
+        IROM_BASE + irom_offset % MB,
+        irom_len,
+        segment
+    )
+    .unwrap();
+
+    // Fall back to the default layout, unless a board crate puts its own memory.x in the library
+    // search path. A memory.x in the working directory of the linker is found first either way.
+    if !feature("CUSTOM_MEMORY_X") {
+        writeln!(
+            File::create(out.join("memory.x")).unwrap(),
+            "INCLUDE memory-default.x"
+        )
+        .unwrap();
+    }
+
+    // Read-only data in flash, or in DRAM with the `rodata-in-dram` feature
+    let rodata = if feature("RODATA_IN_DRAM") {
+        "dram_seg"
+    } else {
+        "irom_seg"
+    };
+    writeln!(
+        File::create(out.join("rodata.x")).unwrap(),
+        "REGION_ALIAS(\"rodata_seg\", {});",
+        rodata
+    )
+    .unwrap();
+
+    // Default handlers for all interrupts, from the interrupt table in the proc macro crate
+    let interrupts = env::var("DEP_XTENSA_LX106_RT_PROC_MACROS_INTERRUPTS").unwrap();
+    let mut provides = File::create(out.join("interrupts.x")).unwrap();
+    for interrupt in interrupts.split(',') {
+        let name = interrupt.split(':').nth(1).unwrap();
+        writeln!(provides, "PROVIDE(__{}_interrupt = __default_interrupt);", name).unwrap();
+    }
+
     println!("cargo:rustc-link-search={}", out.display());
 
-    // Only re-run the build script when memory.x is changed,
+    // Only re-run the build script when link.x is changed,
     // instead of when any part of the source code changes.
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=link.x");
 }
@@ -0,0 +1,8 @@
+//! Tests of the parts of `xtensa-lx106-rt` which don't depend on the lx106, built on the host
+//!
+//! The runtime itself only builds for the xtensa target, which has no test harness. Run with
+//! `cargo test` in this directory.
+
+#[cfg(test)]
+#[path = "../../src/heap/free_list.rs"]
+mod free_list;
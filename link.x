@@ -0,0 +1,200 @@
+ENTRY(Reset)
+
+/* Linker script for the ESP8266 */
+
+/* The MEMORY regions dram_seg, vectors_seg, iram_seg and irom_seg come from memory.x. The linker
+   looks for it in its working directory, the root of the workspace, before the library search
+   path. Without one build.rs provides a memory.x including memory-default.x, the layout selected
+   with the flash-*, esp8285 and ota-slot-* features, unless the custom-memory-x feature is
+   enabled. */
+INCLUDE memory.x
+
+/* REGION_ALIAS for rodata_seg, irom_seg or with the rodata-in-dram feature dram_seg, generated by
+   build.rs */
+INCLUDE rodata.x
+
+/* MB of the flash mapped at 0x40200000 by the cache, irom_seg must lie in this segment */
+PROVIDE(_flash_cache_segment = 0);
+
+PROVIDE(__pre_init = DefaultPreInit);
+PROVIDE(__clock_config = DefaultClockConfig);
+
+/* ROM functions */
+PROVIDE(Cache_Read_Enable = 0x40004678);
+PROVIDE(Cache_Read_Disable = 0x400047f0);
+
+/* The stack grows down from _stack_start, by default at the end of DRAM. Both can be changed
+   with e.g. `-C link-arg=--defsym=_stack_size=0x4000` */
+PROVIDE(_stack_size = 0x2000);
+PROVIDE(_stack_start = ORIGIN(dram_seg) + LENGTH(dram_seg));
+
+/* Limits checked at link time, which can be changed like the stack size */
+PROVIDE(_min_stack_size = 0x800);
+PROVIDE(_min_heap_size = 0);
+PROVIDE(_iram_budget = LENGTH(iram_seg));
+PROVIDE(_dram_budget = LENGTH(dram_seg));
+
+PROVIDE(__user_exception = __default_exception);
+PROVIDE(__kernel_exception = __default_exception);
+PROVIDE(__double_exception = __default_double_exception);
+PROVIDE(__nmi_exception = __default_nmi_exception);
+PROVIDE(__debug_exception = __default_debug_exception);
+PROVIDE(__alloc_exception = __default_exception);
+PROVIDE(__stack_overflow = __default_stack_overflow);
+/* PROVIDE(__<name>_interrupt = __default_interrupt) for every interrupt, generated by build.rs */
+/* handlers registered at runtime with `interrupt::register` take precedence over these symbols */
+INCLUDE interrupts.x
+
+/* jumped to with call0 from the vectors, which only reaches IRAM */
+PROVIDE(__naked_user_exception = __default_naked_user_exception);
+PROVIDE(__naked_kernel_exception = __default_naked_kernel_exception);
+PROVIDE(__naked_double_exception = __default_naked_double_exception);
+PROVIDE(__naked_nmi_exception = __default_naked_nmi_exception);
+PROVIDE(__naked_debug_exception = __default_naked_debug_exception);
+PROVIDE(__naked_alloc_exception = __default_naked_alloc_exception);
+
+/* needed to force inclusion of the vectors */
+EXTERN(__default_exception);
+EXTERN(__default_double_exception);
+EXTERN(__default_nmi_exception);
+EXTERN(__default_debug_exception);
+EXTERN(__default_interrupt);
+EXTERN(__default_stack_overflow);
+
+EXTERN(__default_naked_user_exception);
+EXTERN(__default_naked_exception);
+EXTERN(__default_naked_double_exception);
+EXTERN(__default_naked_nmi_exception);
+EXTERN(__default_naked_debug_exception);
+EXTERN(__default_naked_alloc_exception);
+
+/* Define output sections */
+SECTIONS {
+
+  .vectors :
+  {
+    /* lx106 vector offsets from VECBASE: Debug 0x10, NMI 0x20, Kernel 0x30, User 0x50 and
+       Double 0x70. The end of each vector is recorded for the slot size assertions, MAX keeps the
+       linker from failing with a less readable error first */
+    . = 0x0;
+    _init_start = ABSOLUTE(.);
+    . = 0x10;
+    KEEP(*(.DebugException.text));
+    _debug_exception_vector_end = ABSOLUTE(.);
+    . = MAX(., 0x20);
+    KEEP(*(.NMIException.text));
+    _nmi_exception_vector_end = ABSOLUTE(.);
+    . = MAX(., 0x30);
+    KEEP(*(.KernelException.text));
+    _kernel_exception_vector_end = ABSOLUTE(.);
+    . = MAX(., 0x50);
+    KEEP(*(.UserException.text));
+    _user_exception_vector_end = ABSOLUTE(.);
+    . = MAX(., 0x70);
+    KEEP(*(.DoubleException.text));
+    _double_exception_vector_end = ABSOLUTE(.);
+    . = MAX(., 0x80);
+
+    _init_end = ABSOLUTE(.);
+  } > vectors_seg
+
+  .rwtext :
+  {
+    *(.rwtext.literal .rwtext .rwtext.literal.* .rwtext.text.*)
+    . = ALIGN(4);
+    *(.iram.data .iram.data.*)
+  } > iram_seg
+
+  /* The rest of IRAM is used by the IRAM heap */
+  _iram_heap_start = ALIGN(ADDR(.rwtext) + SIZEOF(.rwtext), 8);
+  _iram_heap_end = ORIGIN(iram_seg) + LENGTH(iram_seg);
+
+  /* Code runs from flash through the cache, enabled by Reset */
+  .text :
+    {
+      _text_start = ABSOLUTE(.);
+      *(.literal .text .literal.* .text.*)
+      _text_end = ABSOLUTE(.);
+    } > irom_seg
+
+    .rotext :
+    {
+      *(.rotext.literal .rotext.text .rotext.literal.* .rotext.text.*)
+    } > irom_seg
+
+  /* Shared RAM */
+  .dram0.bss (NOLOAD) :
+  {
+    . = ALIGN (8);
+    _bss_start = ABSOLUTE(.);
+    *(.bss)
+    *(.bss.*)
+    . = ALIGN (8);
+    _bss_end = ABSOLUTE(.);
+  } > dram_seg
+
+  .dram0.data :
+  {
+    _data_start = ABSOLUTE(.);
+    *(.data)
+    *(.data.*)
+    _data_end = ABSOLUTE(.);
+  } >dram_seg
+
+  _sidata = LOADADDR(.dram0.data);
+
+  /* Flash only supports 32-bit loads, with rodata in flash byte and halfword loads are emulated
+     by the exception handler in exception/load_store.rs */
+  .rodata :
+  {
+    . = ALIGN(4);
+    _rodata_start = ABSOLUTE(.);
+    *(.rodata)
+    *(.rodata.*)
+    _rodata_end = ABSOLUTE(.);
+  } > rodata_seg
+
+  /* Read by the exception handlers, which must not depend on the flash cache */
+  .dram0.rodata :
+  {
+    . = ALIGN(4);
+    _exception_handlers_start = ABSOLUTE(.);
+    KEEP(*(.exception_handlers))
+    _exception_handlers_end = ABSOLUTE(.);
+    . = ALIGN(4);
+    _heap_start = ABSOLUTE(.);
+  } >dram_seg
+
+  /* The heap ends where the stack ends */
+  _stack_end = _stack_start - _stack_size;
+  _heap_end = _stack_end;
+}
+
+/* Vector slots, a vector overflowing its slot would overlap the next vector */
+ASSERT(_debug_exception_vector_end <= _init_start + 0x20,
+  "_DebugExceptionVector is larger than its slot at VECBASE + 0x10..0x20")
+ASSERT(_nmi_exception_vector_end <= _init_start + 0x30,
+  "_NMIExceptionVector is larger than its slot at VECBASE + 0x20..0x30")
+ASSERT(_kernel_exception_vector_end <= _init_start + 0x50,
+  "_KernelExceptionVector is larger than its slot at VECBASE + 0x30..0x50")
+ASSERT(_user_exception_vector_end <= _init_start + 0x70,
+  "_UserExceptionVector is larger than its slot at VECBASE + 0x50..0x70")
+ASSERT(_double_exception_vector_end <= _init_start + 0x80,
+  "_DoubleExceptionVector is larger than its slot at VECBASE + 0x70..0x80")
+ASSERT(_init_start % 1024 == 0,
+  "the vectors (_init_start) must be aligned to 1KB to be used as VECBASE, check vectors_seg")
+
+/* Stack and heap */
+ASSERT(_stack_start % 16 == 0, "_stack_start must be aligned to 16 bytes")
+ASSERT(_stack_start <= ORIGIN(dram_seg) + LENGTH(dram_seg), "the stack is outside of dram_seg")
+ASSERT(_stack_size >= _min_stack_size, "the stack is smaller than _min_stack_size")
+ASSERT(_heap_start <= _heap_end,
+  "the static data in DRAM overlaps the stack, reduce _stack_size or the static data")
+ASSERT(_heap_end - _heap_start >= _min_heap_size,
+  "the heap is smaller than _min_heap_size, reduce _stack_size or the static data")
+
+/* Memory budgets */
+ASSERT(ADDR(.rwtext) + SIZEOF(.rwtext) - ORIGIN(iram_seg) <= _iram_budget,
+  "the code in IRAM (.rwtext) exceeds _iram_budget")
+ASSERT(_heap_start - ORIGIN(dram_seg) <= _dram_budget,
+  "the static data in DRAM exceeds _dram_budget")
@@ -0,0 +1,14 @@
+include!("src/interrupts.rs");
+
+fn main() {
+    // Pass the interrupt table to the build script of `xtensa-lx106-rt` as
+    // `DEP_XTENSA_LX106_RT_PROC_MACROS_INTERRUPTS`
+    let table = INTERRUPTS
+        .iter()
+        .map(|(line, name)| format!("{}:{}", line, name))
+        .collect::<Vec<_>>()
+        .join(",");
+    println!("cargo:interrupts={}", table);
+
+    println!("cargo:rerun-if-changed=src/interrupts.rs");
+}
@@ -0,0 +1,23 @@
+/// Level 1 interrupt lines of the lx106 and their names
+///
+/// This table is the single source for the `#[interrupt]` attribute, the `InterruptType` enum and
+/// dispatch in `xtensa-lx106-rt` and the `PROVIDE` lines of its linker script, which receives it
+/// through the `links` metadata emitted by `build.rs`.
+///
+/// Line 14 is the NMI, which is handled by `#[nmi]` instead.
+pub const INTERRUPTS: &[(u8, &str)] = &[
+    (0, "wdev"),
+    (1, "slc"),
+    (2, "spi"),
+    (3, "rtc"),
+    (4, "gpio"),
+    (5, "uart"),
+    (6, "ccompare"),
+    (7, "soft"),
+    (8, "wdt"),
+    (9, "timer1"),
+    (10, "timer2"),
+    (11, "int11"),
+    (12, "int12"),
+    (13, "int13"),
+];
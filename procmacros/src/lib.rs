@@ -4,16 +4,22 @@
 
 extern crate proc_macro;
 
+mod interrupts;
+
+use crate::interrupts::INTERRUPTS;
 use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::quote;
 use std::collections::HashSet;
 use syn::{
     parse, parse_macro_input, spanned::Spanned, AttrStyle, AttributeArgs, Attribute, FnArg, Ident,
-    Item, ItemFn, ItemStatic, ReturnType, Stmt, Type, Visibility,
+    Item, ItemFn, ItemStatic, Lifetime, ReturnType, Stmt, Type, Visibility,
 };
 
 /// Marks a function as the main function to be called on program start
+///
+/// With the `executor` feature of the runtime the function can also be an `async fn() -> !`,
+/// which is run by `executor::block_on`.
 #[proc_macro_attribute]
 pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
     let mut f = parse_macro_input!(input as ItemFn);
@@ -37,7 +43,7 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
     if !valid_signature {
         return parse::Error::new(
             f.span(),
-            "`#[entry]` function must have signature `[unsafe] fn() -> !`",
+            "`#[entry]` function must have signature `[unsafe] [async] fn() -> !`",
         )
             .to_compile_error()
             .into();
@@ -58,56 +64,38 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
         &format!("__xtensa_lx106_rt_{}", f.sig.ident),
         Span::call_site(),
     );
-    f.sig.inputs.extend(statics.iter().map(|statik| {
-        let ident = &statik.ident;
-        let ty = &statik.ty;
-        let attrs = &statik.attrs;
-
-        // Note that we use an explicit `'static` lifetime for the entry point arguments. This makes
-        // it more flexible, and is sound here, since the entry will not be called again, ever.
-        syn::parse::<FnArg>(
-            quote!(#[allow(non_snake_case)] #(#attrs)* #ident: &'static mut #ty).into(),
-        )
-            .unwrap()
-    }));
+    // Note that we use an explicit `'static` lifetime for the entry point arguments. This makes
+    // it more flexible, and is sound here, since the entry will not be called again, ever.
+    f.sig.inputs.extend(statics_as_inputs(&statics, Some(syn::parse_quote!('static))));
     f.block.stmts = stmts;
 
     let tramp_ident = Ident::new(&format!("{}_trampoline", f.sig.ident), Span::call_site());
     let ident = &f.sig.ident;
 
-    let resource_args = statics
-        .iter()
-        .map(|statik| {
-            let (ref cfgs, ref attrs) = extract_cfgs(statik.attrs.clone());
-            let ident = &statik.ident;
-            let ty = &statik.ty;
-            let expr = &statik.expr;
-            quote! {
-                #(#cfgs)*
-                {
-                    #(#attrs)*
-                    static mut #ident: #ty = #expr;
-                    &mut #ident
-                }
-            }
-        })
-        .collect::<Vec<_>>();
+    let resource_args = resource_args(&statics);
 
     if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Entry) {
         return error;
     }
 
+    place_in_ram(&mut f.attrs, false);
+
     let (ref cfgs, ref attrs) = extract_cfgs(f.attrs.clone());
 
+    let call = quote!(#ident(#(#resource_args),*));
+    let call = if f.sig.asyncness.is_some() {
+        quote!(xtensa_lx106_rt::executor::block_on(#call))
+    } else {
+        call
+    };
+
     quote!(
         #(#cfgs)*
         #(#attrs)*
         #[doc(hidden)]
         #[export_name = "main"]
         pub unsafe extern "C" fn #tramp_ident() {
-            #ident(
-                #(#resource_args),*
-            )
+            #call
         }
 
         #[inline(always)]
@@ -158,7 +146,8 @@ pub fn pre_init(args: TokenStream, input: TokenStream) -> TokenStream {
         return error;
     }
 
-    let attrs = f.attrs;
+    let mut attrs = f.attrs;
+    place_in_ram(&mut attrs, false);
     let ident = f.sig.ident;
     let block = f.block;
 
@@ -173,34 +162,291 @@ pub fn pre_init(args: TokenStream, input: TokenStream) -> TokenStream {
 
 /// Marks a function as the exception handler
 ///
+/// Without arguments the function becomes the fallback handler for all user exceptions which are
+/// not handled otherwise.
+///
+/// With an exception cause as argument the function only handles exceptions of that cause and
+/// must return whether it handled the exception. Cause-specific handlers from all crates are
+/// tried, in link order, before the fallback handler is called.
+///
 /// ## Example
 ///
 /// ```ignore
-/// use xtensa_lx106_rt::{exception, ExceptionContext};
+/// use xtensa_lx106_rt::{exception, ExceptionCause, ExceptionContext};
 ///
 /// #[exception]
-/// fn exception_handler(cause: ExceptionCause, save_frame: &ExceptionContext) {
-///     // ...
+/// fn exception_handler(cause: ExceptionCause, save_frame: &mut ExceptionContext) {
+///     // changes to the frame are restored when returning from the handler
+///     save_frame.set_pc(save_frame.pc() + 3);
+/// }
+///
+/// #[exception(Syscall)]
+/// fn syscall_handler(cause: ExceptionCause, save_frame: &mut ExceptionContext) -> bool {
+///     // skip the syscall instruction
+///     save_frame.set_pc(save_frame.pc() + 3);
+///     true
 /// }
 /// ```
 #[proc_macro_attribute]
 pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
+    if args.is_empty() {
+        return fixed_exception(input, FixedException::User);
+    }
+
     let mut f = parse_macro_input!(input as ItemFn);
 
+    let attr_args = parse_macro_input!(args as AttributeArgs);
+
+    let cause = match attr_args.as_slice() {
+        [syn::NestedMeta::Meta(syn::Meta::Path(path))] if path.get_ident().is_some() => {
+            path.get_ident().unwrap().clone()
+        }
+        _ => {
+            return parse::Error::new(
+                Span::call_site(),
+                "This attribute accepts no arguments or a single exception cause",
+            )
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Exception) {
+        return error;
+    }
+
+    let valid_signature = f.sig.constness.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.abi.is_none()
+        && f.sig.inputs.len() <= 2
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none()
+        && match f.sig.output {
+        ReturnType::Default => false,
+        ReturnType::Type(_, ref ty) => match **ty {
+            Type::Path(ref path) => path.qself.is_none() && path.path.is_ident("bool"),
+            _ => false,
+        },
+    };
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[exception(Cause)]` handlers must have signature `[unsafe] fn([ExceptionCause[, &mut ExceptionContext]]) -> bool`",
+        )
+            .to_compile_error()
+            .into();
+    }
+
+    let mut args = match f.sig.inputs.len() {
+        0 => vec![],
+        1 => vec![quote!(cause)],
+        _ => vec![quote!(cause), quote!(frame)],
+    };
+
+    let (statics, stmts) = match extract_static_muts(f.block.stmts) {
+        Err(e) => return e.to_compile_error().into(),
+        Ok(x) => x,
+    };
+
+    f.sig.ident = Ident::new(&format!("__xtensa_lx_106_{}", f.sig.ident), Span::call_site());
+    f.sig.inputs.extend(statics_as_inputs(&statics, None));
+    f.block.stmts = stmts;
+    args.extend(resource_args(&statics));
+
+    let tramp_ident = Ident::new(&format!("{}_trampoline", f.sig.ident), Span::call_site());
+    let handler_ident = Ident::new(
+        &format!("{}_HANDLER", f.sig.ident).to_ascii_uppercase(),
+        Span::call_site(),
+    );
+    let ident = &f.sig.ident;
+
+    // exception handlers always live in IRAM, `#[ram]` is accepted but implied
+    place_in_ram(&mut f.attrs, true);
+
+    let (ref cfgs, ref attrs) = extract_cfgs(f.attrs.clone());
+
+    quote!(
+        #(#cfgs)*
+        #(#attrs)*
+        #[doc(hidden)]
+        pub unsafe fn #tramp_ident(
+            cause: xtensa_lx106_rt::ExceptionCause,
+            frame: &mut xtensa_lx106_rt::ExceptionContext
+        ) -> bool {
+            #ident(
+                #(#args),*
+            )
+        }
+
+        #(#cfgs)*
+        #[doc(hidden)]
+        #[used]
+        #[link_section = ".exception_handlers"]
+        static #handler_ident: xtensa_lx106_rt::exception::ExceptionHandler =
+            xtensa_lx106_rt::exception::ExceptionHandler {
+                cause: xtensa_lx106_rt::ExceptionCause::#cause,
+                handler: #tramp_ident,
+            };
+
+        #[inline(always)]
+        #f
+    )
+        .into()
+}
+
+/// Marks a function as the kernel exception handler
+///
+/// ## Example
+///
+/// ```ignore
+/// use xtensa_lx106_rt::{kernel_exception, ExceptionCause, ExceptionContext};
+///
+/// #[kernel_exception]
+/// fn kernel_exception_handler(cause: ExceptionCause, save_frame: &mut ExceptionContext) {
+///     // ...
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn kernel_exception(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    fixed_exception(input, FixedException::Kernel)
+}
+
+/// Marks a function as the double exception handler
+///
+/// ## Example
+///
+/// ```ignore
+/// use xtensa_lx106_rt::{double_exception, ExceptionCause, ExceptionContext};
+///
+/// #[double_exception]
+/// fn double_exception_handler(cause: ExceptionCause, save_frame: &mut ExceptionContext) -> ! {
+///     // ...
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn double_exception(args: TokenStream, input: TokenStream) -> TokenStream {
     if !args.is_empty() {
         return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
             .to_compile_error()
             .into();
     }
 
+    fixed_exception(input, FixedException::Double)
+}
+
+/// Marks a function as the non-maskable interrupt handler
+///
+/// ## Example
+///
+/// ```ignore
+/// use xtensa_lx106_rt::{nmi, ExceptionContext};
+///
+/// #[nmi]
+/// fn nmi_handler(save_frame: &mut ExceptionContext) {
+///     // ...
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn nmi(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    fixed_exception(input, FixedException::Nmi)
+}
+
+/// Marks a function as the debug exception handler
+///
+/// The handler receives the value of the `DEBUGCAUSE` register instead of an exception cause.
+///
+/// ## Example
+///
+/// ```ignore
+/// use xtensa_lx106_rt::{debug_exception, ExceptionContext};
+///
+/// #[debug_exception]
+/// fn debug_exception_handler(debug_cause: u32, save_frame: &mut ExceptionContext) {
+///     // ...
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn debug_exception(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    fixed_exception(input, FixedException::Debug)
+}
+
+/// Exception handlers bound to a single symbol provided by `link.x`
+enum FixedException {
+    User,
+    Kernel,
+    Double,
+    Nmi,
+    Debug,
+}
+
+impl FixedException {
+    fn attribute(&self) -> &'static str {
+        match self {
+            FixedException::User => "exception",
+            FixedException::Kernel => "kernel_exception",
+            FixedException::Double => "double_exception",
+            FixedException::Nmi => "nmi",
+            FixedException::Debug => "debug_exception",
+        }
+    }
+
+    fn export_name(&self) -> &'static str {
+        match self {
+            FixedException::User => "__user_exception",
+            FixedException::Kernel => "__kernel_exception",
+            FixedException::Double => "__double_exception",
+            FixedException::Nmi => "__nmi_exception",
+            FixedException::Debug => "__debug_exception",
+        }
+    }
+
+    /// Type and name of the cause argument passed before the frame, if any
+    fn cause(&self) -> Option<(proc_macro2::TokenStream, &'static str)> {
+        match self {
+            FixedException::User | FixedException::Kernel | FixedException::Double => {
+                Some((quote!(xtensa_lx106_rt::ExceptionCause), "ExceptionCause"))
+            }
+            FixedException::Debug => Some((quote!(u32), "u32")),
+            FixedException::Nmi => None,
+        }
+    }
+}
+
+/// Shared implementation of the attributes binding a function to an exception symbol
+fn fixed_exception(input: TokenStream, kind: FixedException) -> TokenStream {
+    let mut f = parse_macro_input!(input as ItemFn);
+
     if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Exception) {
         return error;
     }
 
+    let cause = kind.cause();
+    let max_inputs = if cause.is_some() { 2 } else { 1 };
+
     let valid_signature = f.sig.constness.is_none()
         && f.vis == Visibility::Inherited
         && f.sig.abi.is_none()
-        && f.sig.inputs.len() <= 2
+        && f.sig.inputs.len() <= max_inputs
         && f.sig.generics.params.is_empty()
         && f.sig.generics.where_clause.is_none()
         && f.sig.variadic.is_none()
@@ -214,18 +460,23 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
     };
 
     if !valid_signature {
+        let signature = match &cause {
+            Some((_, name)) => format!("[unsafe] fn([{}[, &mut ExceptionContext]]) [-> !]", name),
+            None => "[unsafe] fn([&mut ExceptionContext]) [-> !]".to_string(),
+        };
         return parse::Error::new(
             f.span(),
-            "`#[exception]` handlers must have signature `[unsafe] fn([&ExceptionContext][, &ExceptionContext]}) [-> !]`",
+            format!("`#[{}]` handlers must have signature `{}`", kind.attribute(), signature),
         )
             .to_compile_error()
             .into();
     }
 
-    let args = match f.sig.inputs.len() {
-        0 => quote!(),
-        1 => quote!(cause),
-        _ => quote!(cause, frame),
+    let mut args = match (f.sig.inputs.len(), &cause) {
+        (0, _) => vec![],
+        (1, Some(_)) => vec![quote!(cause)],
+        (1, None) => vec![quote!(frame)],
+        _ => vec![quote!(cause), quote!(frame)],
     };
 
     let (statics, stmts) = match extract_static_muts(f.block.stmts) {
@@ -234,17 +485,18 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
     };
 
     f.sig.ident = Ident::new(&format!("__xtensa_lx_106_{}", f.sig.ident), Span::call_site());
-    f.sig.inputs.extend(statics.iter().map(|statik| {
-        let ident = &statik.ident;
-        let ty = &statik.ty;
-        let attrs = &statik.attrs;
-        syn::parse::<FnArg>(quote!(#[allow(non_snake_case)] #(#attrs)* #ident: &mut #ty).into())
-            .unwrap()
-    }));
+    f.sig.inputs.extend(statics_as_inputs(&statics, None));
     f.block.stmts = stmts;
+    args.extend(resource_args(&statics));
 
     let tramp_ident = Ident::new(&format!("{}_trampoline", f.sig.ident), Span::call_site());
     let ident = &f.sig.ident;
+    let export_name = kind.export_name();
+
+    let cause_input = cause.map(|(ty, _)| quote!(cause: #ty,));
+
+    // exception handlers always live in IRAM, `#[ram]` is accepted but implied
+    place_in_ram(&mut f.attrs, true);
 
     let (ref cfgs, ref attrs) = extract_cfgs(f.attrs.clone());
 
@@ -252,13 +504,13 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
         #(#cfgs)*
         #(#attrs)*
         #[doc(hidden)]
-        #[export_name = "__user_exception"]
+        #[export_name = #export_name]
         pub unsafe extern "C" fn #tramp_ident(
-            cause: xtensa_lx106_rt::ExceptionCause,
-            frame: xtensa_lx106_rt::ExceptionContext
+            #cause_input
+            frame: &mut xtensa_lx106_rt::ExceptionContext
         ) {
             #ident(
-                #args
+                #(#args),*
             )
         }
 
@@ -269,6 +521,24 @@ pub fn exception(args: TokenStream, input: TokenStream) -> TokenStream {
 }
 
 /// Marks a function as the interrupt handler for the given interrupt type
+///
+/// The interrupt can be given by name or by line number.
+///
+/// ## Example
+///
+/// ```ignore
+/// use xtensa_lx106_rt::{interrupt, ExceptionContext};
+///
+/// #[interrupt(gpio)]
+/// fn gpio_handler(save_frame: &mut ExceptionContext) {
+///     // ...
+/// }
+///
+/// #[interrupt(10)]
+/// fn timer2_handler() {
+///     // ...
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
     let mut f: ItemFn = syn::parse(input).expect("`#[interrupt]` must be applied to a function");
@@ -297,28 +567,37 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
 
     let ty = match &attr_args[0] {
         syn::NestedMeta::Lit(syn::Lit::Str(lit_str)) => lit_str.value(),
+        syn::NestedMeta::Lit(syn::Lit::Int(lit_int)) => lit_int.base10_digits().to_string(),
         syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.get_ident().is_some() => path.get_ident().unwrap().to_string(),
         _ => {
             return parse::Error::new(
                 Span::call_site(),
-                "This attribute accepts a string attribute",
+                "This attribute accepts an interrupt name or line number",
             )
                 .to_compile_error()
                 .into();
         }
     }.to_ascii_lowercase();
 
-    match ty.as_str() {
-        "slc" | "spi" | "gpio" | "uart" | "ccompare" | "soft" | "timer1" => (),
-        _ => {
+    let ty = match INTERRUPTS
+        .iter()
+        .find(|(line, name)| *name == ty || line.to_string() == ty)
+    {
+        Some((_, name)) => name,
+        None => {
+            let supported = INTERRUPTS
+                .iter()
+                .map(|(line, name)| format!("{} ({})", name, line))
+                .collect::<Vec<_>>()
+                .join(", ");
             return parse::Error::new(
                 Span::call_site(),
-                format!("Invalid interrupt type {}, the following types are supported:  slc, spi, gpio, uart, ccompare, soft and timer1", ty),
+                format!("Invalid interrupt type {}, the following types are supported: {}", ty, supported),
             )
                 .to_compile_error()
                 .into();
         }
-    }
+    };
 
     if let Err(error) = check_attr_whitelist(&f.attrs, WhiteListCaller::Interrupt) {
         return error;
@@ -345,7 +624,7 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
     if !valid_signature {
         return parse::Error::new(
             f.span(),
-            "`#[interrupt]` handlers must have signature `[unsafe] fn([u32[, &ExceptionContext]]) [-> !]`",
+            "`#[interrupt]` handlers must have signature `[unsafe] fn([&mut ExceptionContext]) [-> !]`",
         )
             .to_compile_error()
             .into();
@@ -356,42 +635,22 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
         Ok(x) => x,
     };
 
-    let args = match f.sig.inputs.len() {
-        0 => quote!(),
-        1 => quote!(frame),
+    let mut args = match f.sig.inputs.len() {
+        0 => vec![],
+        1 => vec![quote!(frame)],
         _ => unreachable!()
     };
 
     f.sig.ident = Ident::new(&format!("__xtensa_lx_106_{}", f.sig.ident), Span::call_site());
-    f.sig.inputs.extend(statics.iter().map(|statik| {
-        let ident = &statik.ident;
-        let ty = &statik.ty;
-        let attrs = &statik.attrs;
-        syn::parse::<FnArg>(quote!(#[allow(non_snake_case)] #(#attrs)* #ident: &mut #ty).into())
-            .unwrap()
-    }));
+    f.sig.inputs.extend(statics_as_inputs(&statics, None));
     f.block.stmts = stmts;
+    args.extend(resource_args(&statics));
 
     let tramp_ident = Ident::new(&format!("{}_trampoline", f.sig.ident), Span::call_site());
     let ident = &f.sig.ident;
 
-    let resource_args = statics
-        .iter()
-        .map(|statik| {
-            let (ref cfgs, ref attrs) = extract_cfgs(statik.attrs.clone());
-            let ident = &statik.ident;
-            let ty = &statik.ty;
-            let expr = &statik.expr;
-            quote! {
-                #(#cfgs)*
-                {
-                    #(#attrs)*
-                    static mut #ident: #ty = #expr;
-                    &mut #ident
-                }
-            }
-        })
-        .collect::<Vec<_>>();
+    // interrupt handlers always live in IRAM, `#[ram]` is accepted but implied
+    place_in_ram(&mut f.attrs, true);
 
     let (ref cfgs, ref attrs) = extract_cfgs(f.attrs.clone());
 
@@ -401,10 +660,10 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
         #[doc(hidden)]
         #[export_name = #ident_s]
         pub unsafe extern "C" fn #tramp_ident(
-            frame: &xtensa_lx106_rt::exception::ExceptionContext
+            frame: &mut xtensa_lx106_rt::exception::ExceptionContext
         ) {
-                #ident(#args
-                #(,#resource_args)*
+            #ident(
+                #(#args),*
             )
         }
 
@@ -414,6 +673,173 @@ pub fn interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
         .into()
 }
 
+/// Generates `InterruptType` and the link-time handler declarations from the interrupt table
+#[doc(hidden)]
+#[proc_macro]
+pub fn interrupt_table(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        return parse::Error::new(Span::call_site(), "This macro accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    let variants = INTERRUPTS
+        .iter()
+        .map(|(_, name)| Ident::new(&name.to_ascii_uppercase(), Span::call_site()))
+        .collect::<Vec<_>>();
+    let lines = INTERRUPTS.iter().map(|(line, _)| *line);
+    let symbols = INTERRUPTS
+        .iter()
+        .map(|(_, name)| Ident::new(&format!("__{}_interrupt", name), Span::call_site()))
+        .collect::<Vec<_>>();
+    let count = INTERRUPTS.len();
+    let linked_handlers = (0..16u8).map(|line| {
+        match INTERRUPTS.iter().find(|(interrupt_line, _)| *interrupt_line == line) {
+            Some((_, name)) => {
+                let symbol = Ident::new(&format!("__{}_interrupt", name), Span::call_site());
+                quote!(Some(#symbol))
+            }
+            None => quote!(None),
+        }
+    });
+
+    quote!(
+        #[repr(u8)]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub enum InterruptType {
+            #(#variants = #lines,)*
+        }
+
+        extern "C" {
+            #(fn #symbols(context: &mut ExceptionContext);)*
+        }
+
+        impl InterruptType {
+            /// All level 1 interrupts, in order of their line number
+            pub const ALL: [InterruptType; #count] = [#(InterruptType::#variants),*];
+        }
+
+        /// Handlers bound at link time, indexed by line number
+        ///
+        /// Placed in DRAM, so interrupts can be dispatched while the flash cache is disabled.
+        #[link_section = ".data"]
+        static LINKED_HANDLERS: [Option<unsafe extern "C" fn(&mut ExceptionContext)>; INTERRUPT_LINES] = [
+            #(#linked_handlers,)*
+        ];
+    )
+        .into()
+}
+
+/// Places a function or static in RAM
+///
+/// Functions are placed in IRAM (`.rwtext`) so they can still be executed while the flash cache
+/// is disabled, statics are placed in DRAM (`.data`).
+///
+/// ## Example
+///
+/// ```ignore
+/// use xtensa_lx106_rt::ram;
+///
+/// #[ram]
+/// fn called_from_isr() {
+///     // ...
+/// }
+///
+/// #[ram]
+/// static TABLE: [u8; 4] = [1, 2, 3, 4];
+/// ```
+#[proc_macro_attribute]
+pub fn ram(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    let item = parse_macro_input!(input as Item);
+
+    let (attrs, section) = match &item {
+        Item::Fn(f) => (&f.attrs, RWTEXT),
+        Item::Static(s) => (&s.attrs, ".data"),
+        _ => {
+            return parse::Error::new(
+                item.span(),
+                "`#[ram]` can only be applied to functions and statics",
+            )
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    if let Some(attr) = attrs.iter().find(|attr| eq(attr, "link_section")) {
+        return parse::Error::new(
+            attr.span(),
+            "`#[ram]` can not be combined with `#[link_section]`",
+        )
+            .to_compile_error()
+            .into();
+    }
+
+    quote!(
+        #[link_section = #section]
+        #item
+    )
+        .into()
+}
+
+/// Section of the functions placed in IRAM
+const RWTEXT: &str = ".rwtext";
+
+/// Places a function in IRAM if it has a `#[ram]` attribute, or always if `implied`
+///
+/// The `#[ram]` attributes are removed, an explicit `#[link_section]` takes precedence.
+fn place_in_ram(attrs: &mut Vec<Attribute>, implied: bool) {
+    let len = attrs.len();
+    attrs.retain(|attr| !eq(attr, "ram"));
+    let ram = attrs.len() != len;
+
+    if (ram || implied) && !attrs.iter().any(|attr| eq(attr, "link_section")) {
+        attrs.push(syn::parse_quote!(#[link_section = #RWTEXT]));
+    }
+}
+
+/// Function arguments replacing the `static mut` vars of a handler, with an optional lifetime
+fn statics_as_inputs(statics: &[ItemStatic], lifetime: Option<Lifetime>) -> Vec<FnArg> {
+    statics
+        .iter()
+        .map(|statik| {
+            let ident = &statik.ident;
+            let ty = &statik.ty;
+            let attrs = &statik.attrs;
+            syn::parse::<FnArg>(
+                quote!(#[allow(non_snake_case)] #(#attrs)* #ident: &#lifetime mut #ty).into(),
+            )
+                .unwrap()
+        })
+        .collect()
+}
+
+/// Expressions passing the `static mut` vars of a handler as arguments
+fn resource_args(statics: &[ItemStatic]) -> Vec<proc_macro2::TokenStream> {
+    statics
+        .iter()
+        .map(|statik| {
+            let (ref cfgs, ref attrs) = extract_cfgs(statik.attrs.clone());
+            let ident = &statik.ident;
+            let ty = &statik.ty;
+            let expr = &statik.expr;
+            quote! {
+                #(#cfgs)*
+                {
+                    #(#attrs)*
+                    static mut #ident: #ty = #expr;
+                    &mut #ident
+                }
+            }
+        })
+        .collect()
+}
+
 /// Extracts `static mut` vars from the beginning of the given statements
 fn extract_static_muts(
     stmts: impl IntoIterator<Item=Stmt>,
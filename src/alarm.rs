@@ -0,0 +1,208 @@
+//! Alarm queue multiplexed onto CCOMPARE0
+//!
+//! The runtime owns CCOMPARE0 and the CCOMPARE interrupt, keeping it armed for the nearest
+//! deadline of all scheduled alarms. Callbacks are called from the CCOMPARE interrupt handler.
+
+use crate::interrupt::{self, InterruptType};
+use crate::timer::{self, Instant};
+use crate::ExceptionContext;
+use core::mem::transmute;
+use core::time::Duration;
+
+/// Maximum number of alarms that can be scheduled at the same time
+pub const MAX_ALARMS: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    deadline: Instant,
+    /// Period in ticks for periodic alarms, 0 for one-shot alarms
+    period: u64,
+    callback: fn(*const ()),
+    ctx: *const (),
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    /// Incremented every time the slot is freed, invalidating old `Alarm` handles
+    generation: u32,
+    entry: Option<Entry>,
+}
+
+static mut SLOTS: [Slot; MAX_ALARMS] = [Slot {
+    generation: 0,
+    entry: None,
+}; MAX_ALARMS];
+
+/// Whether the CCOMPARE interrupt handler has been installed
+static mut INSTALLED: bool = false;
+
+/// Handle to a scheduled alarm
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Alarm {
+    index: u8,
+    generation: u32,
+}
+
+impl Alarm {
+    /// Cancel the alarm, returns `false` if it had already fired or was cancelled before
+    ///
+    /// Periodic alarms keep firing until they are cancelled.
+    pub fn cancel(self) -> bool {
+        interrupt::free(|_| unsafe {
+            let slot = &mut SLOTS[self.index as usize];
+            if slot.generation != self.generation || slot.entry.is_none() {
+                return false;
+            }
+            free_slot(slot);
+            rearm();
+            true
+        })
+    }
+
+    /// Check whether the alarm is still scheduled
+    pub fn is_scheduled(self) -> bool {
+        interrupt::free(|_| unsafe {
+            let slot = &SLOTS[self.index as usize];
+            slot.generation == self.generation && slot.entry.is_some()
+        })
+    }
+}
+
+/// Schedule `callback` to be called with `ctx` at `deadline`
+///
+/// Returns `None` if all `MAX_ALARMS` alarms are in use. Callbacks run in the CCOMPARE interrupt
+/// handler, like any interrupt handler they should be placed in IRAM with `#[ram]`.
+pub fn schedule<T: Sync>(
+    deadline: Instant,
+    callback: fn(&'static T),
+    ctx: &'static T,
+) -> Option<Alarm> {
+    insert(deadline, 0, callback, ctx)
+}
+
+/// Schedule `callback` to be called with `ctx` every `period`, starting at `first`
+///
+/// Returns `None` if all `MAX_ALARMS` alarms are in use.
+pub fn schedule_periodic<T: Sync>(
+    first: Instant,
+    period: Duration,
+    callback: fn(&'static T),
+    ctx: &'static T,
+) -> Option<Alarm> {
+    let period = Instant::from_ticks(0)
+        .checked_add(period)
+        .map_or(u64::MAX, Instant::ticks)
+        .max(1);
+    insert(first, period, callback, ctx)
+}
+
+fn insert<T: Sync>(
+    deadline: Instant,
+    period: u64,
+    callback: fn(&'static T),
+    ctx: &'static T,
+) -> Option<Alarm> {
+    install();
+
+    interrupt::free(|_| unsafe {
+        let index = SLOTS.iter().position(|slot| slot.entry.is_none())?;
+        let slot = &mut SLOTS[index];
+        slot.entry = Some(Entry {
+            deadline,
+            period,
+            callback: transmute::<fn(&'static T), fn(*const ())>(callback),
+            ctx: ctx as *const T as *const (),
+        });
+        rearm();
+        Some(Alarm {
+            index: index as u8,
+            generation: slot.generation,
+        })
+    })
+}
+
+/// Take over the CCOMPARE interrupt
+fn install() {
+    let install = interrupt::free(|_| unsafe {
+        let install = !INSTALLED;
+        INSTALLED = true;
+        install
+    });
+    if install {
+        interrupt::register(InterruptType::CCOMPARE, on_ccompare, &());
+        interrupt::enable_interrupt(InterruptType::CCOMPARE);
+    }
+}
+
+unsafe fn free_slot(slot: &mut Slot) {
+    slot.entry = None;
+    slot.generation = slot.generation.wrapping_add(1);
+}
+
+/// Arm CCOMPARE0 for the nearest deadline
+///
+/// Without alarms it is still armed for the longest interval to keep the 64-bit clock up to date.
+#[link_section = ".rwtext"]
+unsafe fn rearm() {
+    let nearest = SLOTS
+        .iter()
+        .filter_map(|slot| slot.entry.map(|entry| entry.deadline))
+        .min()
+        .unwrap_or_else(|| Instant::from_ticks(u64::MAX));
+    timer::arm(nearest);
+}
+
+/// Rearm CCOMPARE0 after the CPU frequency changed, the deadlines are kept in ticks
+pub(crate) fn rearm_for_frequency_change() {
+    interrupt::free(|_| unsafe {
+        if INSTALLED {
+            rearm();
+        }
+    });
+}
+
+/// Remove the first expired alarm from the queue, rescheduling it if it's periodic
+#[link_section = ".rwtext"]
+fn take_expired(now: Instant) -> Option<(fn(*const ()), *const ())> {
+    interrupt::free(|_| unsafe {
+        let slot = SLOTS
+            .iter_mut()
+            .find(|slot| matches!(slot.entry, Some(entry) if entry.deadline <= now))?;
+        let mut entry = slot.entry?;
+
+        let next = match entry.period {
+            0 => None,
+            period => {
+                // skip missed periods instead of firing them in a burst
+                let missed = (now.ticks() - entry.deadline.ticks()) / period;
+                missed
+                    .checked_add(1)
+                    .and_then(|periods| periods.checked_mul(period))
+                    .and_then(|offset| entry.deadline.ticks().checked_add(offset))
+            }
+        };
+
+        match next {
+            Some(deadline) => {
+                entry.deadline = Instant::from_ticks(deadline);
+                slot.entry = Some(entry);
+            }
+            // one-shot alarms, and periodic alarms whose next deadline doesn't fit in the clock
+            None => free_slot(slot),
+        }
+
+        Some((entry.callback, entry.ctx))
+    })
+}
+
+#[link_section = ".rwtext"]
+fn on_ccompare(_: &'static (), _: &mut ExceptionContext) {
+    let now = Instant::now();
+
+    // callbacks are called outside of the critical section, so they can schedule new alarms
+    while let Some((callback, ctx)) = take_expired(now) {
+        callback(ctx);
+    }
+
+    interrupt::free(|_| unsafe { rearm() });
+}
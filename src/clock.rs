@@ -0,0 +1,228 @@
+//! Clock configuration
+//!
+//! The CPU and APB clocks are generated by the PLL from the crystal. `Reset` configures the clocks
+//! before `__pre_init` with the `ClockConfig` returned by `__clock_config`, which defaults to a
+//! 26MHz crystal and an 80MHz CPU clock. Boards with another crystal can provide their own:
+//!
+//! ```ignore
+//! use xtensa_lx106_rt::clock::{ClockConfig, CpuFrequency, CrystalFrequency};
+//!
+//! #[no_mangle]
+//! fn __clock_config() -> ClockConfig {
+//!     ClockConfig::new(CrystalFrequency::Crystal40MHz, CpuFrequency::Cpu160MHz)
+//! }
+//! ```
+//!
+//! The CPU frequency can be changed at runtime with `set_cpu_frequency`, code depending on the
+//! clocks can be notified of changes with `add_listener`.
+
+use crate::alarm;
+use crate::interrupt;
+use crate::timer;
+use core::ptr::{read_volatile, write_volatile};
+
+const CRYSTAL_26MHZ: (u8, u8) = (136, 145);
+const CRYSTAL_40MHZ: (u8, u8) = (8, 129);
+
+/// APB and base CPU frequency with the PLL configured for the crystal
+const BASE_FREQUENCY: u32 = 80_000_000;
+
+/// DPORT register with the CPU clock doubler
+const DPORT_CPU_CLOCK: *mut u32 = 0x3ff0_0014 as *mut u32;
+const DPORT_CPU_CLOCK_X2: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CrystalFrequency {
+    /// The PLL settings for a 24MHz crystal aren't known, the PLL is configured as for a 26MHz
+    /// crystal and all clocks run at 24/26 of their nominal frequency. `apb_frequency` and
+    /// `cpu_frequency` report the scaled frequencies.
+    Crystal24MHz,
+    Crystal26MHz,
+    Crystal40MHz,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuFrequency {
+    Cpu80MHz,
+    Cpu160MHz,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockConfig {
+    pub crystal: CrystalFrequency,
+    pub cpu: CpuFrequency,
+}
+
+impl ClockConfig {
+    pub const fn new(crystal: CrystalFrequency, cpu: CpuFrequency) -> Self {
+        ClockConfig { crystal, cpu }
+    }
+
+    /// Frequency of the APB clock in Hz, used by the peripherals
+    pub const fn apb_frequency(self) -> u32 {
+        match self.crystal {
+            CrystalFrequency::Crystal24MHz => (BASE_FREQUENCY as u64 * 24 / 26) as u32,
+            CrystalFrequency::Crystal26MHz | CrystalFrequency::Crystal40MHz => BASE_FREQUENCY,
+        }
+    }
+
+    /// Frequency of the CPU clock in Hz
+    pub const fn cpu_frequency(self) -> u32 {
+        match self.cpu {
+            CpuFrequency::Cpu80MHz => self.apb_frequency(),
+            CpuFrequency::Cpu160MHz => self.apb_frequency() * 2,
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig::new(CrystalFrequency::Crystal26MHz, CpuFrequency::Cpu80MHz)
+    }
+}
+
+static mut CONFIG: ClockConfig =
+    ClockConfig::new(CrystalFrequency::Crystal26MHz, CpuFrequency::Cpu80MHz);
+
+/// Get the current clock configuration
+pub fn config() -> ClockConfig {
+    interrupt::free(|_| unsafe { CONFIG })
+}
+
+/// Get the CPU frequency in Hz
+pub fn cpu_frequency() -> u32 {
+    config().cpu_frequency()
+}
+
+/// Get the APB frequency in Hz
+pub fn apb_frequency() -> u32 {
+    config().apb_frequency()
+}
+
+/// Change the CPU frequency at runtime
+///
+/// Shorthand for `configure` with only the CPU frequency changed.
+pub fn set_cpu_frequency(cpu: CpuFrequency) {
+    configure(ClockConfig { cpu, ..config() });
+}
+
+/// Configure the clocks, this can be done at any time
+///
+/// The switch is made in a critical section. The timers in `timer` are updated for the new CPU
+/// frequency, the pending alarms are rearmed for their deadlines at the new frequency and the
+/// listeners added with `add_listener` are notified.
+pub fn configure(config: ClockConfig) {
+    interrupt::free(|_| unsafe {
+        let old = CONFIG;
+
+        // account for the cycles elapsed at the old frequency
+        timer::Instant::now();
+
+        if config.crystal != old.crystal {
+            configure_pll_for(config.crystal);
+        }
+        set_cpu_clock(config.cpu);
+        set_config(config);
+
+        alarm::rearm_for_frequency_change();
+
+        for listener in LISTENERS.iter().flatten() {
+            listener(old, config);
+        }
+    });
+}
+
+/// Maximum number of listeners notified of clock changes
+pub const MAX_LISTENERS: usize = 8;
+
+static mut LISTENERS: [Option<fn(ClockConfig, ClockConfig)>; MAX_LISTENERS] =
+    [None; MAX_LISTENERS];
+
+/// Add a listener called with the old and new configuration after the clocks are changed
+///
+/// Listeners are called in a critical section, so they can adjust e.g. baud rate dividers before
+/// any other code runs with the new clocks. Returns `false` if all `MAX_LISTENERS` listeners are
+/// in use.
+pub fn add_listener(listener: fn(ClockConfig, ClockConfig)) -> bool {
+    interrupt::free(|_| unsafe {
+        match LISTENERS.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(listener);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Remove a listener, returns `false` if it wasn't added
+pub fn remove_listener(listener: fn(ClockConfig, ClockConfig)) -> bool {
+    interrupt::free(|_| unsafe {
+        let slot = LISTENERS
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(added) if *added as usize == listener as usize));
+        match slot {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Configure the PLL and CPU clock, without touching RAM
+#[link_section = ".rwtext"]
+pub(crate) unsafe fn configure_hardware(config: ClockConfig) {
+    configure_pll_for(config.crystal);
+    set_cpu_clock(config.cpu);
+}
+
+#[link_section = ".rwtext"]
+unsafe fn configure_pll_for(crystal: CrystalFrequency) {
+    match crystal {
+        CrystalFrequency::Crystal24MHz | CrystalFrequency::Crystal26MHz => {
+            configure_pll(CRYSTAL_26MHZ)
+        }
+        CrystalFrequency::Crystal40MHz => configure_pll(CRYSTAL_40MHZ),
+    }
+}
+
+#[link_section = ".rwtext"]
+unsafe fn set_cpu_clock(cpu: CpuFrequency) {
+    let value = read_volatile(DPORT_CPU_CLOCK);
+    match cpu {
+        CpuFrequency::Cpu80MHz => write_volatile(DPORT_CPU_CLOCK, value & !DPORT_CPU_CLOCK_X2),
+        CpuFrequency::Cpu160MHz => write_volatile(DPORT_CPU_CLOCK, value | DPORT_CPU_CLOCK_X2),
+    }
+}
+
+/// Record the configuration applied by `configure_hardware`, once the RAM is initialized
+pub(crate) unsafe fn set_config(config: ClockConfig) {
+    CONFIG = config;
+    timer::set_cpu_frequency(config.cpu_frequency());
+}
+
+unsafe fn rom_i2c_write_reg(block: u8, host_id: u8, reg_add: u8, data: u8) {
+    let ctrl = (1 << 24) | ((data as u32) << 16) | ((reg_add as u32) << 8) | (block as u32);
+    let addr = ((host_id as u32 * 4) + 0x60000d00) as *mut u32;
+    write_volatile(addr, ctrl);
+    loop {
+        let a9 = read_volatile(addr);
+        if (a9 >> 25) & 1 == 0 {
+            return;
+        }
+    }
+}
+
+unsafe fn configure_pll((reg1, reg2): (u8, u8)) {
+    rom_i2c_write_reg(103, 4, 1, reg1);
+    rom_i2c_write_reg(103, 4, 2, reg2);
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn DefaultClockConfig() -> ClockConfig {
+    ClockConfig::default()
+}
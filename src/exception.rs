@@ -1,4 +1,6 @@
 mod assembly;
+#[cfg(any(feature = "load-store-emulation", not(feature = "rodata-in-dram")))]
+mod load_store;
 
 /// EXCCAUSE register values
 ///
@@ -6,7 +8,7 @@ mod assembly;
 /// which vector to the user, kernel, or double-exception vectors).
 ///
 #[allow(unused)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u32)]
 pub enum ExceptionCause {
     /// Illegal Instruction
@@ -96,31 +98,191 @@ pub enum ExceptionCause {
 /// State of the CPU saved when entering exception or interrupt
 ///
 /// Must be aligned with assembly frame format in assembly.rs
+///
+/// Changes made to PC, PS, A0-A15 and SAR are written back to the CPU when returning from the
+/// handler, for double exceptions the PC is returned to through DEPC. For interrupts INTENABLE is
+/// recalculated from the enabled interrupts and their priorities before returning. The other
+/// fields are only informational.
 #[repr(C)]
 #[allow(non_snake_case)]
 #[derive(Debug, Default)]
 pub struct ExceptionContext {
     PC: u32,
     PS: u32,
-    A0: u32,
-    A1: u32,
-    A2: u32,
-    A3: u32,
-    A4: u32,
-    A5: u32,
-    A6: u32,
-    A7: u32,
-    A8: u32,
-    A9: u32,
-    A10: u32,
-    A11: u32,
-    A12: u32,
-    A13: u32,
-    A14: u32,
-    A15: u32,
+    A: [u32; 16],
     SAR: u32,
     EXCCAUSE: u32,
     EXCVADDR: u32,
+    INTERRUPT: u32,
+    INTENABLE: u32,
+    DEPC: u32,
+    EPC3: u32,
+}
+
+macro_rules! context_register {
+    ($field:ident, $get:ident, $set:ident, $get_doc:literal, $set_doc:literal) => {
+        // always inlined, handlers in IRAM must not call into flash
+        #[doc = $get_doc]
+        #[inline(always)]
+        pub fn $get(&self) -> u32 {
+            self.$field
+        }
+
+        #[doc = $set_doc]
+        #[inline(always)]
+        pub fn $set(&mut self, value: u32) {
+            self.$field = value;
+        }
+    };
+}
+
+impl ExceptionContext {
+    context_register!(
+        PC, pc, set_pc,
+        "Get the saved program counter",
+        "Set the saved program counter"
+    );
+    context_register!(
+        PS, ps, set_ps,
+        "Get the saved processor state",
+        "Set the saved processor state"
+    );
+    context_register!(
+        SAR, sar, set_sar,
+        "Get the saved shift amount register",
+        "Set the saved shift amount register"
+    );
+    context_register!(
+        EXCCAUSE, exccause, set_exccause,
+        "Get the saved exception cause",
+        "Set the saved exception cause"
+    );
+    context_register!(
+        EXCVADDR, excvaddr, set_excvaddr,
+        "Get the saved exception virtual address",
+        "Set the saved exception virtual address"
+    );
+    context_register!(
+        INTERRUPT, interrupt, set_interrupt,
+        "Get the saved pending interrupts",
+        "Set the saved pending interrupts"
+    );
+    context_register!(
+        INTENABLE, intenable, set_intenable,
+        "Get the saved enabled interrupts",
+        "Set the saved enabled interrupts"
+    );
+    context_register!(
+        DEPC, depc, set_depc,
+        "Get the saved double exception program counter",
+        "Set the saved double exception program counter"
+    );
+    context_register!(
+        EPC3, epc3, set_epc3,
+        "Get the saved level 3 (NMI) program counter",
+        "Set the saved level 3 (NMI) program counter"
+    );
+
+    /// Get the saved value of the general purpose register `A<index>`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is larger than 15
+    #[inline]
+    pub fn a(&self, index: usize) -> u32 {
+        self.A[index]
+    }
+
+    /// Set the saved value of the general purpose register `A<index>`
+    ///
+    /// Note that A1 is the stack pointer of the interrupted code.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is larger than 15
+    #[inline]
+    pub fn set_a(&mut self, index: usize, value: u32) {
+        self.A[index] = value;
+    }
+}
+
+/// Handler for a single exception cause, registered using `#[exception(Cause)]`
+///
+/// All registered handlers are collected by the linker in the `.exception_handlers` section
+#[doc(hidden)]
+#[repr(C)]
+pub struct ExceptionHandler {
+    pub cause: ExceptionCause,
+    pub handler: unsafe fn(ExceptionCause, &mut ExceptionContext) -> bool,
+}
+
+/// Size of an entry in the `.exception_handlers` section
+const HANDLER_SIZE: usize = core::mem::size_of::<ExceptionHandler>();
+
+/// Try all registered handlers for the exception cause, returns `true` if the exception was handled
+///
+/// Walks the handlers with a plain `while` loop, iterators and the derived `PartialEq` would be
+/// calls into flash without optimizations.
+#[inline(always)]
+fn handle_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext) -> bool {
+    extern "C" {
+        // These symbols come from `link.x`
+        static _exception_handlers_start: u32;
+        static _exception_handlers_end: u32;
+    }
+
+    unsafe {
+        let mut address = &_exception_handlers_start as *const u32 as usize;
+        let end = &_exception_handlers_end as *const u32 as usize;
+        while address < end {
+            let handler = &*(address as *const ExceptionHandler);
+            if handler.cause as u32 == cause as u32 && (handler.handler)(cause, save_frame) {
+                return true;
+            }
+            address += HANDLER_SIZE;
+        }
+        false
+    }
+}
+
+/// Size of the save frame pushed by the exception handlers, `XT_STK_FRMSZ`
+pub(crate) const FRAME_SIZE: usize = 256;
+
+/// Context switch hook, called before returning from a level 1 interrupt or user exception
+///
+/// Returns the saved context to resume, either the context it's called with or the saved context
+/// of another stack.
+pub type SwitchHook = fn(&mut ExceptionContext) -> *mut ExceptionContext;
+
+static mut SWITCH_HOOK: Option<SwitchHook> = None;
+
+/// Install a context switch hook, replacing the previous one
+///
+/// The hook is only called when returning to code running outside of interrupt handlers and
+/// critical sections, with level 1 interrupts masked. It is the primitive schedulers like `thread` switch stacks with.
+///
+/// # Safety
+///
+/// The hook must return a context saved by the exception handlers, or one prepared like it at
+/// `FRAME_SIZE` bytes below the stack pointer it sets in A1.
+pub unsafe fn set_switch_hook(hook: Option<SwitchHook>) {
+    crate::interrupt::free(|_| SWITCH_HOOK = hook);
+}
+
+/// PS.INTLEVEL, set by `rsil` in critical sections
+const PS_INTLEVEL_MASK: u32 = 0xf;
+
+/// Get the context to resume from the switch hook
+#[inline(always)]
+fn switch_context(save_frame: &mut ExceptionContext) -> *mut ExceptionContext {
+    unsafe {
+        // exceptions can be taken inside critical sections, which must not be switched out
+        let interruptible = save_frame.ps() & PS_INTLEVEL_MASK == 0;
+        match SWITCH_HOOK {
+            Some(hook) if interruptible && crate::interrupt::at_base_priority() => hook(save_frame),
+            _ => save_frame,
+        }
+    }
 }
 
 #[naked]
@@ -129,7 +291,7 @@ pub struct ExceptionContext {
 unsafe extern "C" fn _DebugExceptionVector() {
     llvm_asm!(
         "
-        wsr a0, EXCSAVE1 // preserve a0
+        wsr a0, EXCSAVE2 // preserve a0, debug exceptions are taken at level 2
         call0 __naked_debug_exception     // used as long jump
         "
     );
@@ -141,7 +303,7 @@ unsafe extern "C" fn _DebugExceptionVector() {
 unsafe extern "C" fn _NMIExceptionVector() {
     llvm_asm!(
         "
-        wsr a0, EXCSAVE1 // preserve a0
+        wsr a0, EXCSAVE3 // preserve a0, the NMI is a level 3 interrupt
         call0 __naked_nmi_exception     // used as long jump
         "
     );
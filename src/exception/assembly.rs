@@ -23,7 +23,10 @@ global_asm!(
     .set XT_STK_SAR,            72
     .set XT_STK_EXCCAUSE,       76
     .set XT_STK_EXCVADDR,       80
-    .set XT_INT_ENABLE,    84
+    .set XT_STK_INTERRUPT,      84
+    .set XT_STK_INTENABLE,      88
+    .set XT_STK_DEPC,           92
+    .set XT_STK_EPC3,           96
 
     .set XT_STK_BASESAVE,      240
     .set XT_STK_FRMSZ,         256  // needs to be multiple of 16 and at least 16 free
@@ -35,6 +38,14 @@ global_asm!(
     .set PS_EXCM,          0x00000010
     .set PS_UM,            0x00000020
     .set PS_WOE,           0x00040000
+
+    // Call a handler which can be overridden, and placed in flash, by the user
+    //
+    // call0 only reaches 512KB, flash is further away from IRAM than that.
+    .macro CALL_HANDLER target:req
+    movi    a0, \target
+    callx0  a0
+    .endm
     "
 );
 
@@ -83,6 +94,38 @@ unsafe extern "C" fn save_context() {
     )
 }
 
+// Check whether the interruptee's stack pointer is above `__stack_limit` once the save frame is
+// pushed and all registers are saved
+//
+// The limit leaves room for one save frame above the end of the stack, so pushing the frame
+// stays inside the stack either way. On overflow `__stack_overflow` is called with the save
+// frame, on the overflowed stack.
+#[cfg(feature = "stack-guard")]
+global_asm!(
+    r#"
+    .macro CHECK_STACK
+
+    l32i    a2, sp, +XT_STK_A1
+    movi    a3, __stack_limit
+    l32i    a3, a3, 0
+    bgeu    a2, a3, 1f                 // the interruptee's stack pointer is above the limit
+
+    mov     a2, sp                     // put address of save frame in a2
+    CALL_HANDLER __stack_overflow
+    1:
+
+    .endm
+    "#
+);
+
+#[cfg(not(feature = "stack-guard"))]
+global_asm!(
+    r#"
+    .macro CHECK_STACK
+    .endm
+    "#
+);
+
 global_asm!(
     r#"
     .macro SAVE_CONTEXT level:req
@@ -99,9 +142,7 @@ global_asm!(
     s32i    a0, sp, +XT_STK_PC         // save interruptee's PC
 
     .ifc \level,double
-    rsr     a0, EXCSAVE2               // ok to reuse EXCSAVE7 for double exception as long as
-                                       // double exception is not in first couple of instructions
-                                       // of level 7 handler
+    rsr     a0, EXCSAVE1               // stored by the double exception vector
     .else
     rsr     a0, EXCSAVE\level
     .endif
@@ -117,15 +158,49 @@ global_asm!(
     s32i    a0, sp, +XT_STK_EXCVADDR
     .endif
 
+    .ifc \level,2
+    rsr     a0, EPS2
+    s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+
+    rsr     a0, EXCCAUSE
+    s32i    a0, sp, +XT_STK_EXCCAUSE
+    rsr     a0, EXCVADDR
+    s32i    a0, sp, +XT_STK_EXCVADDR
+    .endif
+
+    .ifc \level,3
+    rsr     a0, EPS3
+    s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+
+    rsr     a0, EXCCAUSE
+    s32i    a0, sp, +XT_STK_EXCCAUSE
+    rsr     a0, EXCVADDR
+    s32i    a0, sp, +XT_STK_EXCVADDR
+    .endif
+
     .ifc \level,double
+    rsr     a0, PS                     // a double exception leaves PS unchanged
+    s32i    a0, sp, +XT_STK_PS         // save interruptee's PS
+
     rsr     a0, EXCCAUSE
     s32i    a0, sp, +XT_STK_EXCCAUSE
     rsr     a0, EXCVADDR
     s32i    a0, sp, +XT_STK_EXCVADDR
+    rsr     a0, DEPC
+    s32i    a0, sp, +XT_STK_DEPC
     .endif
 
+    rsr     a0, INTERRUPT
+    s32i    a0, sp, +XT_STK_INTERRUPT
+    rsr     a0, INTENABLE
+    s32i    a0, sp, +XT_STK_INTENABLE
+    rsr     a0, EPC3
+    s32i    a0, sp, +XT_STK_EPC3
+
     call0   save_context
 
+    CHECK_STACK
+
     .endm
     "#
 );
@@ -174,6 +249,27 @@ global_asm!(
     wsr     a0, EPC\level
     .endif
 
+    .ifc \level,2
+    l32i    a0, sp, +XT_STK_PS        // retrieve interruptee's PS
+    wsr     a0, EPS2
+    l32i    a0, sp, +XT_STK_PC        // retrieve interruptee's PC
+    wsr     a0, EPC2
+    .endif
+
+    .ifc \level,3
+    l32i    a0, sp, +XT_STK_PS        // retrieve interruptee's PS
+    wsr     a0, EPS3
+    l32i    a0, sp, +XT_STK_PC        // retrieve interruptee's PC
+    wsr     a0, EPC3
+    .endif
+
+    .ifc \level,double
+    l32i    a0, sp, +XT_STK_PS        // retrieve interruptee's PS
+    wsr     a0, PS
+    l32i    a0, sp, +XT_STK_PC        // retrieve interruptee's PC
+    wsr     a0, DEPC
+    .endif
+
     l32i    a0, sp, +XT_STK_A0        // retrieve interruptee's A0
     l32i    sp, sp, +XT_STK_A1        // remove exception frame
     rsync                             // ensure PS and EPC written
@@ -199,29 +295,32 @@ unsafe extern "C" fn __default_naked_user_exception() {
         beqi    a2, 4, .Level1Interrupt   // cause 4 is interrupt
 
         mov     a3, sp                    // put address of save frame in a3
-        call0   __user_exception          // call handler <= actual call!
+        call0   __dispatch_user_exception // call handler <= actual call!
 
-        j .RestoreContext
+        j .SwitchContext
 
         .Level1Interrupt:
-        rsr.INTERRUPT a2                  // out interrupt type in a2
-        rsr.INTENABLE a3
-        s32i a3, sp, +XT_INT_ENABLE       // save enabled interrupts
+        l32i a2, sp, +XT_STK_INTERRUPT    // put interrupt type in a2
+        l32i a3, sp, +XT_STK_INTENABLE    // enabled interrupts, saved by SAVE_CONTEXT
         and a2, a2, a3
 
         wsr.INTCLEAR a2
 
-        movi a4, 0
-        wsr.INTENABLE a4                  // disable interrupts
-        rsil a4, 0
+        movi a4, PS_UM | 1
+        wsr.PS a4                         // clear PS.EXCM with level 1 interrupts still masked,
+        rsync                             // the trampoline unmasks them by priority
 
         mov     a3, sp                    // put address of save frame in a3
         call0   __interrupt_trampoline    // call handler <= actual call!
 
-        l32i a3, sp, +XT_INT_ENABLE
-        wsr.INTENABLE a3                  // re enable interrupts
+        l32i a3, sp, +XT_STK_INTENABLE
+        wsr.INTENABLE a3                  // restore enabled interrupts, updated by trampoline
+
+        .SwitchContext:
+        mov     a2, sp                    // put address of save frame in a2
+        call0   __switch_context          // returns the save frame to resume in a2
+        mov     sp, a2
 
-        .RestoreContext:
         RESTORE_CONTEXT 1
 
         .byte 0x00, 0x30, 0x00            // rfe
@@ -232,8 +331,11 @@ unsafe extern "C" fn __default_naked_user_exception() {
 
 /// Handle Double Exceptions by storing full context and then calling regular function
 ///
+/// Returns with `rfde` to the PC in DEPC, with PS.EXCM still set like when the double exception
+/// was raised.
+///
 /// # Input:
-///    * A0 stored in ???
+///    * A0 stored in EXCSAVE1
 #[naked]
 #[no_mangle]
 #[link_section = ".rwtext"]
@@ -244,11 +346,12 @@ unsafe extern "C" fn __default_naked_double_exception() {
 
         l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
         mov     a3, sp                    // put address of save frame in a3
-        call0   __double_exception        // call handler <= actual call!
+        CALL_HANDLER __double_exception   // call handler <= actual call!
 
         RESTORE_CONTEXT double
 
-        .byte 0x00, 0x30, 0x00            // rfe
+        .byte 0x00, 0x32, 0x00            // rfde
+                                          // TODO: 20200509, not supported in llvm yet
         "
     )
 }
@@ -268,7 +371,7 @@ unsafe extern "C" fn __default_naked_kernel_exception() {
         l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
 
         mov     a3, sp                    // put address of save frame in a3
-        call0   __kernel_exception               // call handler <= actual call!
+        call0   __dispatch_kernel_exception // call handler <= actual call!
 
         RESTORE_CONTEXT 1
 
@@ -280,48 +383,54 @@ unsafe extern "C" fn __default_naked_kernel_exception() {
 
 /// Handle NMI Exceptions by storing full context and then calling regular function
 ///
+/// The NMI is a level 3 interrupt which can arrive while a level 1 exception or interrupt is
+/// being handled, so only the level 3 registers (EXCSAVE3, EPC3 and EPS3) are used.
+///
 /// # Input:
-///    * A0 stored in EXCSAVE1
+///    * A0 stored in EXCSAVE3
 #[naked]
 #[no_mangle]
 #[link_section = ".rwtext"]
 unsafe extern "C" fn __default_naked_nmi_exception() {
     llvm_asm!(
         "
-        SAVE_CONTEXT 1
+        SAVE_CONTEXT 3
 
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
-
-        mov     a3, sp                    // put address of save frame in a3
-        call0   __nmi_exception               // call handler <= actual call!
+        mov     a2, sp                    // put address of save frame in a2
+        CALL_HANDLER __nmi_exception      // call handler <= actual call!
 
-        RESTORE_CONTEXT 1
+        RESTORE_CONTEXT 3
 
-        .byte 0x00, 0x30, 0x00            // rfe
+        .byte 0x10, 0x33, 0x00            // rfi 3
+                                          // TODO: 20200509, not supported in llvm yet
         "
     )
 }
 
 /// Handle Debug Exceptions by storing full context and then calling regular function
 ///
+/// Debug exceptions are taken at level 2, so only the level 2 registers (EXCSAVE2, EPC2 and
+/// EPS2) are used.
+///
 /// # Input:
-///    * A0 stored in EXCSAVE1
+///    * A0 stored in EXCSAVE2
 #[naked]
 #[no_mangle]
 #[link_section = ".rwtext"]
 unsafe extern "C" fn __default_naked_debug_exception() {
     llvm_asm!(
         "
-        SAVE_CONTEXT 1
+        SAVE_CONTEXT 2
 
-        l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
+        rsr     a2, DEBUGCAUSE            // put debug cause in a2
 
         mov     a3, sp                    // put address of save frame in a3
-        call0   __debug_exception               // call handler <= actual call!
+        CALL_HANDLER __debug_exception    // call handler <= actual call!
 
-        RESTORE_CONTEXT 1
+        RESTORE_CONTEXT 2
 
-        .byte 0x00, 0x30, 0x00            // rfe
+        .byte 0x10, 0x32, 0x00            // rfi 2
+                                          // TODO: 20200509, not supported in llvm yet
         "
     )
 }
@@ -341,7 +450,7 @@ unsafe extern "C" fn __default_naked_alloc_exception() {
         l32i    a2, sp, +XT_STK_EXCCAUSE  // put cause in a2
 
         mov     a3, sp                    // put address of save frame in a3
-        call0   __alloc_exception         // call handler <= actual call!
+        CALL_HANDLER __alloc_exception    // call handler <= actual call!
 
         RESTORE_CONTEXT 1
 
@@ -350,18 +459,61 @@ unsafe extern "C" fn __default_naked_alloc_exception() {
     )
 }
 
+extern "C" {
+    fn __user_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext);
+    fn __kernel_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext);
+}
+
+/// Give the runtime a chance to handle the exception before calling the user handler
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __dispatch_user_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext) {
+    if !super::handle_exception(cause, save_frame) {
+        unsafe { __user_exception(cause, save_frame) }
+    }
+}
+
+/// Give the runtime a chance to handle the exception before calling the kernel handler
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __dispatch_kernel_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext) {
+    if !super::handle_exception(cause, save_frame) {
+        unsafe { __kernel_exception(cause, save_frame) }
+    }
+}
+
+/// Let the switch hook pick the context to resume, returns the address of its save frame
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __switch_context(save_frame: &mut ExceptionContext) -> *mut ExceptionContext {
+    super::switch_context(save_frame)
+}
+
 #[no_mangle]
 #[link_section = ".rwtext"]
-extern "C" fn __default_exception(cause: ExceptionCause, save_frame: &ExceptionContext) {
+extern "C" fn __default_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext) {
     panic!("Exception: {:?}, {:08x?}", cause, save_frame)
 }
 
 #[no_mangle]
 #[link_section = ".rwtext"]
-extern "C" fn __default_double_exception(cause: ExceptionCause, save_frame: &ExceptionContext) {
+extern "C" fn __default_double_exception(cause: ExceptionCause, save_frame: &mut ExceptionContext) {
     panic!("Double Exception: {:?}, {:08x?}", cause, save_frame)
 }
+
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __default_nmi_exception(save_frame: &mut ExceptionContext) {
+    panic!("NMI: {:08x?}", save_frame)
+}
+
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __default_debug_exception(debug_cause: u32, save_frame: &mut ExceptionContext) {
+    panic!("Debug Exception: {:08x?}, {:08x?}", debug_cause, save_frame)
+}
+
 #[no_mangle]
 #[link_section = ".rwtext"]
-extern "C" fn __default_interrupt(_save_frame: &ExceptionContext) {
+extern "C" fn __default_interrupt(_save_frame: &mut ExceptionContext) {
 }
\ No newline at end of file
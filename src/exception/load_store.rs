@@ -0,0 +1,80 @@
+//! Emulation of byte and halfword loads from IRAM and flash
+//!
+//! The instruction bus only supports aligned 32-bit access, any narrower load from IRAM or
+//! the flash mapped `irom_seg` raises a `LoadStoreError`. The faulting instruction is decoded
+//! and emulated with an aligned 32-bit read.
+
+use crate::exception::ExceptionHandler;
+use crate::flash_cache::IRAM;
+use crate::{ExceptionCause, ExceptionContext};
+use core::ptr::read_volatile;
+
+const IROM_START: u32 = 0x4020_0000;
+const IROM_END: u32 = 0x4030_0000;
+
+/// Opcode of the `LSAI` instruction group (`op0` field)
+const OP0_LSAI: u32 = 0x2;
+
+/// Load instruction (`r` field of `LSAI`)
+const R_L8UI: u32 = 0x0;
+const R_L16UI: u32 = 0x1;
+const R_L16SI: u32 = 0x9;
+
+/// Length of the `RRI8` encoded load instructions
+const INSTRUCTION_LENGTH: u32 = 3;
+
+#[used]
+#[link_section = ".exception_handlers"]
+static LOAD_STORE_EMULATION: ExceptionHandler = ExceptionHandler {
+    cause: ExceptionCause::LoadStoreError,
+    handler: emulate,
+};
+
+/// Try to emulate the faulting load, returns `true` if the load was emulated
+#[link_section = ".rwtext"]
+fn emulate(_cause: ExceptionCause, save_frame: &mut ExceptionContext) -> bool {
+    let address = save_frame.excvaddr();
+    if !IRAM.contains(&address) && !(IROM_START..IROM_END).contains(&address) {
+        return false;
+    }
+
+    let pc = save_frame.pc();
+    let instruction = unsafe { read_instruction(pc) };
+
+    let op0 = instruction & 0xf;
+    let t = ((instruction >> 4) & 0xf) as usize;
+    let r = (instruction >> 12) & 0xf;
+
+    if op0 != OP0_LSAI {
+        return false;
+    }
+
+    let word = unsafe { read_volatile((address & !3) as *const u32) };
+    let shift = (address & 3) * 8;
+
+    let value = match r {
+        R_L8UI => (word >> shift) & 0xff,
+        R_L16UI if address & 1 == 0 => (word >> shift) & 0xffff,
+        R_L16SI if address & 1 == 0 => (word >> shift) as u16 as i16 as i32 as u32,
+        _ => return false,
+    };
+
+    save_frame.set_a(t, value);
+    save_frame.set_pc(pc + INSTRUCTION_LENGTH);
+
+    true
+}
+
+/// Read the 24-bit instruction at `pc` using only aligned 32-bit reads
+#[inline(always)]
+unsafe fn read_instruction(pc: u32) -> u32 {
+    let aligned = (pc & !3) as *const u32;
+    let shift = (pc & 3) * 8;
+    let low = read_volatile(aligned);
+    if pc & 3 <= 1 {
+        // the instruction doesn't cross a word boundary
+        return (low >> shift) & 0x00ff_ffff;
+    }
+    let high = read_volatile(aligned.add(1));
+    ((low >> shift) | (high << (32 - shift))) & 0x00ff_ffff
+}
@@ -0,0 +1,170 @@
+//! Minimal async executor
+//!
+//! `block_on` polls a single future, idling with `waiti` while it is pending. Wakers raise the
+//! SOFT interrupt so a wake from outside of an interrupt handler also ends the idle state.
+//!
+//! With this feature `#[entry]` also accepts `async fn main() -> !`.
+
+use crate::interrupt::{self, InterruptType, Registration, INTERRUPT_LINES};
+use crate::ExceptionContext;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::{read_volatile, write_volatile};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+mod time_driver;
+
+/// Set by the waker, cleared before every poll
+static mut WOKEN: bool = false;
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+fn waker_clone(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+#[link_section = ".rwtext"]
+fn waker_wake(_: *const ()) {
+    unsafe { write_volatile(&mut WOKEN, true) };
+    interrupt::raise_soft();
+}
+
+fn waker_drop(_: *const ()) {}
+
+/// Run a future to completion, sleeping while it is pending
+///
+/// Takes over the SOFT interrupt. Interrupts are enabled while sleeping, this should not be called
+/// from a critical section or interrupt handler.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = future;
+    // the future is shadowed and never moved again
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+
+    interrupt::install_soft_handler();
+
+    let waker = unsafe { Waker::from_raw(waker_clone(core::ptr::null())) };
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        unsafe { write_volatile(&mut WOKEN, false) };
+
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        unsafe {
+            let ps = interrupt::set_intlevel_2();
+            if !read_volatile(&WOKEN) {
+                // waiti unmasks the interrupts, so a wakeup can't be missed
+                llvm_asm!("waiti 0" :::: "volatile");
+            }
+            interrupt::restore_ps(ps);
+        }
+    }
+}
+
+/// Interrupt types passed as context to `on_interrupt`
+static TYPES: [InterruptType; InterruptType::ALL.len()] = InterruptType::ALL;
+
+static mut FIRED: [bool; INTERRUPT_LINES] = [false; INTERRUPT_LINES];
+
+const NO_WAKER: Option<Waker> = None;
+static mut WAKERS: [Option<Waker>; INTERRUPT_LINES] = [NO_WAKER; INTERRUPT_LINES];
+
+/// Wait for an interrupt to fire
+///
+/// The interrupt is enabled while waiting and the future replaces the handler of the interrupt,
+/// the handler registered before is restored when it completes or is dropped. When it fires the
+/// interrupt is disabled again, leaving the interrupt source to be cleared before waiting for it
+/// again.
+///
+/// Panics for `SOFT` and `CCOMPARE`, which are owned by the runtime.
+///
+/// ```ignore
+/// loop {
+///     wait_for_interrupt(InterruptType::GPIO).await;
+///     // clear the GPIO interrupt status
+/// }
+/// ```
+pub fn wait_for_interrupt(ty: InterruptType) -> InterruptFuture {
+    assert!(
+        ty != InterruptType::SOFT && ty != InterruptType::CCOMPARE,
+        "interrupt is owned by the runtime"
+    );
+    InterruptFuture { ty, previous: None }
+}
+
+/// Future returned by `wait_for_interrupt`
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct InterruptFuture {
+    ty: InterruptType,
+    /// Registration replaced while waiting
+    previous: Option<Registration>,
+}
+
+impl Future for InterruptFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let line = self.ty as usize;
+        if self.previous.is_none() {
+            // a line fired for an earlier future which was dropped must not complete this one
+            interrupt::free(|_| unsafe {
+                FIRED[line] = false;
+                WAKERS[line] = Some(cx.waker().clone());
+            });
+            let index = TYPES.iter().position(|ty| *ty == self.ty).unwrap();
+            self.previous = Some(interrupt::replace(self.ty, on_interrupt, &TYPES[index]));
+            interrupt::enable_interrupt(self.ty);
+            return Poll::Pending;
+        }
+
+        let fired = interrupt::free(|_| unsafe {
+            if FIRED[line] {
+                FIRED[line] = false;
+                WAKERS[line] = None;
+                return true;
+            }
+            match &WAKERS[line] {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => WAKERS[line] = Some(cx.waker().clone()),
+            }
+            false
+        });
+
+        if fired {
+            if let Some(previous) = self.previous.take() {
+                interrupt::restore(self.ty, previous);
+            }
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for InterruptFuture {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            interrupt::disable_interrupt(self.ty);
+            interrupt::restore(self.ty, previous);
+            interrupt::free(|_| unsafe {
+                FIRED[self.ty as usize] = false;
+                WAKERS[self.ty as usize] = None;
+            });
+        }
+    }
+}
+
+#[link_section = ".rwtext"]
+fn on_interrupt(ty: &'static InterruptType, _: &mut ExceptionContext) {
+    let line = *ty as usize;
+    interrupt::disable_interrupt(*ty);
+    let waker = interrupt::free(|_| unsafe {
+        FIRED[line] = true;
+        WAKERS[line].take()
+    });
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
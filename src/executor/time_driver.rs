@@ -0,0 +1,90 @@
+//! `embassy-time` driver on top of the alarm queue
+//!
+//! Ticks are the ticks of `timer::Instant`, `embassy-time` is configured for the same tick rate.
+
+use crate::alarm::{self, Alarm};
+use crate::interrupt::{self, Mutex};
+use crate::timer::Instant;
+use core::cell::RefCell;
+use core::task::Waker;
+use embassy_time_driver::Driver;
+
+/// Maximum number of wakers waiting for a deadline at the same time
+///
+/// When all slots are in use new wakers are woken immediately, making their timers poll again.
+const MAX_WAKERS: usize = 16;
+
+struct Queue {
+    wakers: [Option<(u64, Waker)>; MAX_WAKERS],
+    /// Alarm scheduled for the nearest deadline
+    alarm: Option<Alarm>,
+}
+
+const NO_WAKER: Option<(u64, Waker)> = None;
+
+static QUEUE: Mutex<RefCell<Queue>> = Mutex::new(RefCell::new(Queue {
+    wakers: [NO_WAKER; MAX_WAKERS],
+    alarm: None,
+}));
+
+impl Queue {
+    fn reschedule(&mut self) {
+        if let Some(alarm) = self.alarm.take() {
+            alarm.cancel();
+        }
+        let nearest = self.wakers.iter().flatten().map(|(at, _)| *at).min();
+        if let Some(at) = nearest {
+            self.alarm = alarm::schedule(Instant::from_ticks(at), on_alarm, &());
+        }
+    }
+}
+
+struct AlarmDriver;
+
+embassy_time_driver::time_driver_impl!(static DRIVER: AlarmDriver = AlarmDriver);
+
+impl Driver for AlarmDriver {
+    fn now(&self) -> u64 {
+        Instant::now().ticks()
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        interrupt::free(|cs| {
+            let mut queue = QUEUE.borrow(cs).borrow_mut();
+
+            let existing = queue
+                .wakers
+                .iter_mut()
+                .flatten()
+                .find(|(_, queued)| queued.will_wake(waker));
+            if let Some((deadline, _)) = existing {
+                *deadline = (*deadline).min(at);
+            } else if let Some(slot) = queue.wakers.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some((at, waker.clone()));
+            } else {
+                waker.wake_by_ref();
+                return;
+            }
+
+            queue.reschedule();
+        });
+    }
+}
+
+#[link_section = ".rwtext"]
+fn on_alarm(_: &'static ()) {
+    let now = Instant::now().ticks();
+    interrupt::free(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        for slot in queue.wakers.iter_mut() {
+            if matches!(slot, Some((at, _)) if *at <= now) {
+                if let Some((_, waker)) = slot.take() {
+                    waker.wake();
+                }
+            }
+        }
+        // the alarm that fired is freed by the alarm queue
+        queue.alarm = None;
+        queue.reschedule();
+    });
+}
@@ -0,0 +1,116 @@
+//! Flash cache control
+//!
+//! Code and read-only data in `irom_seg` are read from the SPI flash through the cache, which maps
+//! one MB segment of the flash at 0x40200000. The cache has to be disabled for direct access to
+//! the flash, while it's disabled anything in `irom_seg` can't be accessed. Flashes larger than
+//! 1MB can be read through the cache by mapping another segment.
+
+use crate::interrupt;
+use core::ops::Range;
+
+extern "C" {
+    // These symbols come from the ROM, their addresses are in `link.x`
+    fn Cache_Read_Enable(block_2mb: u8, block_1mb: u8, cache_size: u8);
+    fn Cache_Read_Disable();
+
+    // This symbol comes from `link.x`
+    static _flash_cache_segment: u8;
+}
+
+/// Size of the flash cache, which takes the upper part of the 64KB of IRAM
+///
+/// Either 16KB or 32KB, the default `iram_seg` in `memory.x` ends where the 32KB cache starts.
+const CACHE_SIZE: u32 = 0x8000;
+
+/// `Cache_Read_Enable` cache size argument, 0 selects 16KB and 1 selects 32KB
+const CACHE_SIZE_ARG: u8 = (CACHE_SIZE == 0x8000) as u8;
+
+/// Addresses of the IRAM below the flash cache
+pub(crate) const IRAM: Range<u32> = 0x4010_0000..0x4011_0000 - CACHE_SIZE;
+
+/// Segment of the flash `irom_seg` in `link.x` is linked for
+pub fn segment() -> u8 {
+    unsafe { &_flash_cache_segment as *const u8 as usize as u8 }
+}
+
+/// Enable the cache, mapping the MB of flash at `segment * 0x100000` at 0x40200000
+///
+/// # Safety
+///
+/// Code in `irom_seg` runs from the mapped segment, any other segment than `segment()` must only
+/// be mapped while running from IRAM.
+#[inline(always)]
+pub unsafe fn enable(segment: u8) {
+    Cache_Read_Enable(segment / 2, segment % 2, CACHE_SIZE_ARG);
+}
+
+/// Disable the cache
+///
+/// # Safety
+///
+/// Nothing in `irom_seg` can be accessed until the cache is enabled again, including code and
+/// read-only data in flash used by interrupt handlers.
+#[inline(always)]
+pub unsafe fn disable() {
+    Cache_Read_Disable();
+}
+
+/// Execute `f` with the flash cache disabled, e.g. to write to the flash
+///
+/// Interrupts not marked with `interrupt::set_iram_safe` are disabled while `f` runs, the `#[nmi]`
+/// handler must be in IRAM. `f` and everything it calls must be in IRAM, mark them `#[ram]`.
+///
+/// # Panics
+///
+/// If `f` isn't in IRAM.
+#[link_section = ".rwtext"]
+pub fn with_cache_disabled<T, R>(f: fn(T) -> R, arg: T) -> R {
+    assert!(in_iram(f as usize), "with_cache_disabled: function not in IRAM");
+    unsafe {
+        let suspended = interrupt::suspend_lines(interrupt::lines_in_flash());
+        let segment = segment();
+        disable();
+        let result = f(arg);
+        enable(segment);
+        interrupt::resume_lines(suspended);
+        result
+    }
+}
+
+/// Execute `f` with another MB segment of the flash mapped, e.g. to read data from a flash larger
+/// than 1MB
+///
+/// Like `with_cache_disabled` interrupts not marked IRAM-safe are disabled, and `f` must be in
+/// IRAM. Data read from the mapped segment must be copied to RAM.
+///
+/// # Panics
+///
+/// If `f` isn't in IRAM.
+#[link_section = ".rwtext"]
+pub fn with_segment<T, R>(segment: u8, f: fn(T) -> R, arg: T) -> R {
+    assert!(in_iram(f as usize), "with_segment: function not in IRAM");
+    unsafe {
+        let suspended = interrupt::suspend_lines(interrupt::lines_in_flash());
+        let linked = self::segment();
+        disable();
+        enable(segment);
+        let result = f(arg);
+        disable();
+        enable(linked);
+        interrupt::resume_lines(suspended);
+        result
+    }
+}
+
+/// Whether a function is in IRAM and can run with the flash cache disabled
+fn in_iram(address: usize) -> bool {
+    IRAM.contains(&(address as u32))
+}
+
+/// Map the segment `irom_seg` is linked for, called by `Reset` before any code in flash runs
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".rwtext"]
+unsafe extern "C" fn __enable_flash_cache() {
+    enable(segment());
+}
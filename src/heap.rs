@@ -0,0 +1,182 @@
+//! Heaps in DRAM and IRAM
+//!
+//! With the `alloc` feature the global allocator manages the DRAM between the static data and the
+//! stack, from `_heap_start` to `_heap_end` in `link.x`. With the `iram-heap` feature the IRAM
+//! left after the code, from `_iram_heap_start` to `_iram_heap_end`, is available through `iram`.
+//! Allocations are made in a critical section, so they can be made from interrupt handlers.
+
+mod free_list;
+
+use self::free_list::Heap;
+pub use self::free_list::Stats;
+use crate::interrupt::{self, Mutex};
+#[cfg(feature = "alloc")]
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::cell::RefCell;
+#[cfg(feature = "iram-heap")]
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "alloc")]
+use core::ptr::{copy_nonoverlapping, null_mut};
+use core::ptr::NonNull;
+#[cfg(feature = "iram-heap")]
+use core::slice;
+
+#[cfg(feature = "alloc")]
+static HEAP: Mutex<RefCell<Heap>> = Mutex::new(RefCell::new(Heap::empty()));
+
+#[cfg(feature = "alloc")]
+struct GlobalHeap;
+
+#[cfg(feature = "alloc")]
+#[global_allocator]
+static ALLOCATOR: GlobalHeap = GlobalHeap;
+
+#[cfg(feature = "alloc")]
+unsafe impl GlobalAlloc for GlobalHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupt::free(|cs| HEAP.borrow(cs).borrow_mut().allocate(layout))
+            .map_or(null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            interrupt::free(|cs| HEAP.borrow(cs).borrow_mut().deallocate(ptr, layout));
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let resized = interrupt::free(|cs| {
+            HEAP.borrow(cs).borrow_mut().resize_in_place(
+                NonNull::new_unchecked(ptr),
+                layout,
+                new_size,
+            )
+        });
+        if resized {
+            return ptr;
+        }
+
+        // move the allocation, copying outside of the critical section
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// Hand the memory between the static data and the stack to the heap, called by `Reset`
+#[cfg(feature = "alloc")]
+pub(crate) unsafe fn init() {
+    extern "C" {
+        // These symbols come from `link.x`
+        static _heap_start: u32;
+        static _heap_end: u32;
+    }
+
+    let start = &_heap_start as *const u32 as usize;
+    let end = &_heap_end as *const u32 as usize;
+    interrupt::free(|cs| HEAP.borrow(cs).borrow_mut().init(start, end));
+}
+
+/// Get the usage of the DRAM heap
+#[cfg(feature = "alloc")]
+pub fn stats() -> Stats {
+    interrupt::free(|cs| HEAP.borrow(cs).borrow().stats())
+}
+
+#[cfg(feature = "iram-heap")]
+static IRAM_HEAP: Mutex<RefCell<Heap>> = Mutex::new(RefCell::new(Heap::empty()));
+
+/// Hand the IRAM after the code to the IRAM heap, called by `Reset`
+#[cfg(feature = "iram-heap")]
+pub(crate) unsafe fn init_iram() {
+    extern "C" {
+        // These symbols come from `link.x`
+        static _iram_heap_start: u32;
+        static _iram_heap_end: u32;
+    }
+
+    let start = &_iram_heap_start as *const u32 as usize;
+    let end = &_iram_heap_end as *const u32 as usize;
+    interrupt::free(|cs| IRAM_HEAP.borrow(cs).borrow_mut().init(start, end));
+}
+
+/// Get a handle to the IRAM heap
+#[cfg(feature = "iram-heap")]
+pub fn iram() -> IramHeap {
+    IramHeap { _private: () }
+}
+
+/// Handle to the heap in the IRAM left after the code
+///
+/// IRAM only supports 32-bit loads and stores, byte and halfword access raises a
+/// `LoadStoreError`. The heap therefore only hands out buffers of words.
+#[cfg(feature = "iram-heap")]
+#[derive(Debug, Copy, Clone)]
+pub struct IramHeap {
+    _private: (),
+}
+
+#[cfg(feature = "iram-heap")]
+impl IramHeap {
+    /// Allocate a zeroed buffer of `len` words, returns `None` if there is no free block large
+    /// enough
+    pub fn allocate(self, len: usize) -> Option<IramBuffer> {
+        let layout = Layout::array::<u32>(len).ok()?;
+        let ptr = interrupt::free(|cs| IRAM_HEAP.borrow(cs).borrow_mut().allocate(layout))?;
+        let ptr = ptr.cast::<u32>();
+        for index in 0..len {
+            unsafe { ptr.as_ptr().add(index).write_volatile(0) };
+        }
+        Some(IramBuffer { ptr, len })
+    }
+
+    /// Get the usage of the IRAM heap
+    pub fn stats(self) -> Stats {
+        interrupt::free(|cs| IRAM_HEAP.borrow(cs).borrow().stats())
+    }
+}
+
+/// Buffer of words allocated in IRAM, freed when dropped
+#[cfg(feature = "iram-heap")]
+#[derive(Debug)]
+pub struct IramBuffer {
+    ptr: NonNull<u32>,
+    len: usize,
+}
+
+// the buffer owns its memory
+#[cfg(feature = "iram-heap")]
+unsafe impl Send for IramBuffer {}
+
+#[cfg(feature = "iram-heap")]
+impl Deref for IramBuffer {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+#[cfg(feature = "iram-heap")]
+impl DerefMut for IramBuffer {
+    fn deref_mut(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+#[cfg(feature = "iram-heap")]
+impl Drop for IramBuffer {
+    fn drop(&mut self) {
+        let layout = Layout::array::<u32>(self.len).unwrap();
+        interrupt::free(|cs| unsafe {
+            IRAM_HEAP
+                .borrow(cs)
+                .borrow_mut()
+                .deallocate(self.ptr.cast(), layout)
+        });
+    }
+}
@@ -0,0 +1,347 @@
+//! First-fit allocator shared by the DRAM and IRAM heaps
+//!
+//! Doesn't depend on the rest of the runtime, so its tests can be run on the host with the
+//! `host-tests` crate.
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{null_mut, NonNull};
+
+/// Allocation granularity, every free block must be able to hold a `FreeBlock`
+const UNIT: usize = size_of::<FreeBlock>();
+
+/// Header of a free block, stored in the block itself
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// First-fit allocator over a free list sorted by address
+///
+/// Adjacent free blocks are merged when deallocating.
+pub(crate) struct Heap {
+    head: *mut FreeBlock,
+    size: usize,
+    used: usize,
+}
+
+// the heap only points into memory it owns
+unsafe impl Send for Heap {}
+
+/// Heap usage
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes allocated, including the padding of allocations to the allocation granularity
+    pub used: usize,
+    /// Bytes free
+    pub free: usize,
+    /// Size of the largest free block, the largest possible allocation
+    pub largest_free_block: usize,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl Heap {
+    pub(crate) const fn empty() -> Self {
+        Heap {
+            head: null_mut(),
+            size: 0,
+            used: 0,
+        }
+    }
+
+    /// Hand the memory from `start` to `end` to the heap
+    ///
+    /// # Safety
+    ///
+    /// The memory must be unused and stay valid for the lifetime of the heap.
+    pub(crate) unsafe fn init(&mut self, start: usize, end: usize) {
+        let start = align_up(start, UNIT);
+        let end = end & !(UNIT - 1);
+        if end <= start {
+            return;
+        }
+
+        self.head = start as *mut FreeBlock;
+        self.head.write(FreeBlock {
+            size: end - start,
+            next: null_mut(),
+        });
+        self.size = end - start;
+        self.used = 0;
+    }
+
+    pub(crate) fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let size = align_up(layout.size().max(1), UNIT);
+        let align = layout.align().max(UNIT);
+
+        unsafe {
+            let mut link: *mut *mut FreeBlock = &mut self.head;
+            while !(*link).is_null() {
+                let block = *link;
+                let block_start = block as usize;
+                let block_end = block_start + (*block).size;
+                let start = align_up(block_start, align);
+
+                if start + size <= block_end {
+                    // replace the block with the free space before and after the allocation,
+                    // both are a multiple of `UNIT`
+                    let mut next = (*block).next;
+                    if start + size < block_end {
+                        let back = (start + size) as *mut FreeBlock;
+                        back.write(FreeBlock {
+                            size: block_end - (start + size),
+                            next,
+                        });
+                        next = back;
+                    }
+                    if start > block_start {
+                        (*block).size = start - block_start;
+                        (*block).next = next;
+                    } else {
+                        *link = next;
+                    }
+
+                    self.used += size;
+                    return NonNull::new(start as *mut u8);
+                }
+
+                link = &mut (*block).next;
+            }
+        }
+
+        None
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this heap with `layout`.
+    pub(crate) unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let start = ptr.as_ptr() as usize;
+        let size = align_up(layout.size().max(1), UNIT);
+        self.used -= size;
+
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// Resize an allocation without moving it, returns `false` if the free block after it is too
+    /// small to grow into
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this heap with `layout`.
+    pub(crate) unsafe fn resize_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> bool {
+        let start = ptr.as_ptr() as usize;
+        let size = align_up(layout.size().max(1), UNIT);
+        let new_size = align_up(new_size.max(1), UNIT);
+
+        if new_size <= size {
+            if new_size < size {
+                // give the end of the allocation back
+                let end = NonNull::new_unchecked((start + new_size) as *mut u8);
+                self.deallocate(end, Layout::from_size_align_unchecked(size - new_size, UNIT));
+            }
+            return true;
+        }
+
+        // grow into the free block directly after the allocation
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+        while !(*link).is_null() && (*link as usize) < start + size {
+            link = &mut (**link).next;
+        }
+        let block = *link;
+        let grow = new_size - size;
+        if block as usize != start + size || (*block).size < grow {
+            return false;
+        }
+
+        let next = (*block).next;
+        let remaining = (*block).size - grow;
+        if remaining > 0 {
+            let back = (start + new_size) as *mut FreeBlock;
+            back.write(FreeBlock {
+                size: remaining,
+                next,
+            });
+            *link = back;
+        } else {
+            *link = next;
+        }
+
+        self.used += grow;
+        true
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        let mut largest_free_block = 0;
+        let mut block = self.head;
+        while !block.is_null() {
+            unsafe {
+                largest_free_block = largest_free_block.max((*block).size);
+                block = (*block).next;
+            }
+        }
+
+        Stats {
+            used: self.used,
+            free: self.size - self.used,
+            largest_free_block,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Heap over `buffer`, which must outlive it
+    fn heap(buffer: &mut [u64]) -> Heap {
+        let start = buffer.as_mut_ptr() as usize;
+        let mut heap = Heap::empty();
+        unsafe { heap.init(start, start + core::mem::size_of_val(buffer)) };
+        heap
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn free_blocks_coalesce() {
+        let mut buffer = [0u64; 128];
+        let mut heap = heap(&mut buffer);
+        let size = heap.stats().free;
+        let block = layout(4 * UNIT, 1);
+
+        let a = heap.allocate(block).unwrap();
+        let b = heap.allocate(block).unwrap();
+        let c = heap.allocate(block).unwrap();
+        assert_eq!(heap.stats().used, 3 * block.size());
+
+        unsafe {
+            heap.deallocate(b, block);
+            assert_eq!(heap.stats().largest_free_block, size - 3 * block.size());
+            // merged with the free blocks before and after it
+            heap.deallocate(c, block);
+            assert_eq!(heap.stats().largest_free_block, size - block.size());
+            // merged with the free block after it
+            heap.deallocate(a, block);
+        }
+        assert_eq!(
+            heap.stats(),
+            Stats {
+                used: 0,
+                free: size,
+                largest_free_block: size,
+            }
+        );
+    }
+
+    #[test]
+    fn alignment_larger_than_unit() {
+        let mut buffer = [0u64; 256];
+        let mut heap = heap(&mut buffer);
+        let size = heap.stats().free;
+        let align = 8 * UNIT;
+
+        // misalign the rest of the heap
+        let small = heap.allocate(layout(1, 1)).unwrap();
+        let aligned = heap.allocate(layout(UNIT, align)).unwrap();
+        assert_eq!(aligned.as_ptr() as usize % align, 0);
+        // the padding before the allocation stays free
+        assert_eq!(heap.stats().used, 2 * UNIT);
+        let next = heap.allocate(layout(UNIT, 1)).unwrap();
+        assert!((next.as_ptr() as usize) < aligned.as_ptr() as usize);
+
+        unsafe {
+            heap.deallocate(aligned, layout(UNIT, align));
+            heap.deallocate(small, layout(1, 1));
+            heap.deallocate(next, layout(UNIT, 1));
+        }
+        assert_eq!(heap.stats().largest_free_block, size);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut buffer = [0u64; 64];
+        let mut heap = heap(&mut buffer);
+        let size = heap.stats().free;
+        let unit = layout(UNIT, 1);
+
+        let blocks = (0..size / UNIT)
+            .map(|_| heap.allocate(unit).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(heap.allocate(layout(1, 1)), None);
+        assert_eq!(heap.stats().free, 0);
+        assert_eq!(heap.stats().largest_free_block, 0);
+
+        unsafe { heap.deallocate(blocks[1], unit) };
+        // a free block too small for the request
+        assert_eq!(heap.allocate(layout(2 * UNIT, 1)), None);
+        assert_eq!(heap.allocate(unit), Some(blocks[1]));
+    }
+
+    #[test]
+    fn resize_in_place() {
+        let mut buffer = [0u64; 128];
+        let mut heap = heap(&mut buffer);
+        let size = heap.stats().free;
+
+        let a = heap.allocate(layout(4 * UNIT, 1)).unwrap();
+        unsafe {
+            // shrinking frees the end of the allocation
+            assert!(heap.resize_in_place(a, layout(4 * UNIT, 1), UNIT));
+            assert_eq!(heap.stats().used, UNIT);
+            assert_eq!(heap.stats().largest_free_block, size - UNIT);
+
+            // growing takes from the free block after it
+            assert!(heap.resize_in_place(a, layout(UNIT, 1), 2 * UNIT));
+            assert_eq!(heap.stats().used, 2 * UNIT);
+
+            // but not past another allocation
+            let b = heap.allocate(layout(UNIT, 1)).unwrap();
+            assert_eq!(b.as_ptr() as usize, a.as_ptr() as usize + 2 * UNIT);
+            assert!(!heap.resize_in_place(a, layout(2 * UNIT, 1), 3 * UNIT));
+
+            heap.deallocate(b, layout(UNIT, 1));
+            // growing into the whole free block
+            assert!(heap.resize_in_place(a, layout(2 * UNIT, 1), size));
+            assert_eq!(heap.stats().free, 0);
+            assert!(!heap.resize_in_place(a, layout(size, 1), size + UNIT));
+
+            heap.deallocate(a, layout(size, 1));
+        }
+        assert_eq!(heap.stats().largest_free_block, size);
+    }
+}
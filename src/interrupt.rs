@@ -1,110 +1,463 @@
 use crate::ExceptionContext;
+use core::mem::transmute;
+use core::ptr::{read_volatile, write_volatile};
 
+pub use critical_section::{CriticalSection, Mutex};
+
+/// Number of interrupt lines of the lx106
+pub(crate) const INTERRUPT_LINES: usize = 16;
+
+// `InterruptType` and the `__*_interrupt` handler declarations, generated from the interrupt table
+// shared with `#[interrupt]` and the linker script
+xtensa_lx106_rt_proc_macros::interrupt_table!();
+
+/// Software priority of an interrupt
+///
+/// While the handler for an interrupt runs only interrupts with a higher priority can preempt it.
 #[repr(u8)]
-pub enum InterruptType {
-    SLC = 1,
-    SPI = 2,
-    GPIO = 4,
-    UART = 5,
-    CCOMPARE = 6,
-    SOFT = 7,
-    WDT = 8,
-    TIMER1 = 9,
-}
-
-extern "C" {
-    fn __slc_interrupt(context: &ExceptionContext);
-    fn __spi_interrupt(context: &ExceptionContext);
-    fn __gpio_interrupt(context: &ExceptionContext);
-    fn __uart_interrupt(context: &ExceptionContext);
-    fn __ccompare_interrupt(context: &ExceptionContext);
-    fn __soft_interrupt(context: &ExceptionContext);
-    fn __wdt_interrupt(context: &ExceptionContext);
-    fn __timer1_interrupt(context: &ExceptionContext);
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Priority1 = 1,
+    Priority2 = 2,
+    Priority3 = 3,
+    Priority4 = 4,
+    Priority5 = 5,
+    Priority6 = 6,
+    Priority7 = 7,
+}
+
+impl Priority {
+    pub const MIN: Priority = Priority::Priority1;
+    pub const MAX: Priority = Priority::Priority7;
+
+    fn from_u8(priority: u8) -> Self {
+        match priority {
+            1 => Priority::Priority1,
+            2 => Priority::Priority2,
+            3 => Priority::Priority3,
+            4 => Priority::Priority4,
+            5 => Priority::Priority5,
+            6 => Priority::Priority6,
+            _ => Priority::Priority7,
+        }
+    }
 }
 
+/// Number of priority levels, including the base level 0 used outside of interrupt handlers
+const PRIORITY_LEVELS: usize = Priority::MAX as usize + 1;
+
+/// Interrupt lines assigned to each priority, all lines start at the lowest priority
+static mut PRIORITY_LINES: [u32; PRIORITY_LEVELS] = [0, !0, 0, 0, 0, 0, 0, 0];
+
+/// Interrupt lines enabled by `enable_interrupt`
+///
+/// `INTENABLE` only contains the enabled lines with a priority above `CURRENT_PRIORITY`
+static mut ENABLED_LINES: u32 = 0;
+
+/// Priority of the running interrupt handler or critical section, 0 outside of handlers
+static mut CURRENT_PRIORITY: u8 = 0;
+
+/// Handlers registered at runtime with `register`, overriding the link-time `__*_interrupt` symbols
+///
+/// Stored in IRAM as plain words, which must only be accessed with (volatile) 32-bit reads and
+/// writes.
+#[link_section = ".iram.data"]
+static mut RUNTIME_HANDLERS: [usize; INTERRUPT_LINES] = [0; INTERRUPT_LINES];
+
+/// Context pointers passed to the handlers in `RUNTIME_HANDLERS`
+#[link_section = ".iram.data"]
+static mut RUNTIME_CONTEXTS: [usize; INTERRUPT_LINES] = [0; INTERRUPT_LINES];
+
 impl InterruptType {
-    const fn mask(self) -> u32 {
+    pub(crate) const fn mask(self) -> u32 {
         1 << self as u8
     }
+}
 
-    fn call(self, context: &ExceptionContext) {
-        unsafe {
-            match self {
-                Self::SLC => __slc_interrupt(context),
-                Self::SPI => __spi_interrupt(context),
-                Self::GPIO => __gpio_interrupt(context),
-                Self::UART => __uart_interrupt(context),
-                Self::CCOMPARE => __ccompare_interrupt(context),
-                Self::SOFT => __soft_interrupt(context),
-                Self::WDT => __wdt_interrupt(context),
-                Self::TIMER1 => __timer1_interrupt(context),
-            }
+/// Volatile 32-bit read of a word in IRAM
+///
+/// Used on the dispatch path instead of `read_volatile`, which without optimizations is a call to
+/// an instance in flash.
+#[inline(always)]
+unsafe fn load(word: &usize) -> usize {
+    let value: usize;
+    llvm_asm!("l32i $0, $1, 0" : "=r"(value) : "r"(word) :: "volatile");
+    value
+}
+
+/// Call the handler registered at runtime for a line, or else the handler bound at link time
+#[inline(always)]
+fn call(line: usize, context: &mut ExceptionContext) {
+    unsafe {
+        let handler = load(&RUNTIME_HANDLERS[line]);
+        if handler != 0 {
+            let handler: fn(*const (), &mut ExceptionContext) = transmute(handler);
+            handler(load(&RUNTIME_CONTEXTS[line]) as *const (), context);
+            return;
+        }
+
+        if let Some(handler) = LINKED_HANDLERS[line] {
+            handler(context);
         }
     }
 }
 
+/// Called by the level 1 interrupt handler with PS.EXCM cleared and level 1 interrupts masked
+///
+/// Pending interrupts are handled from the highest to the lowest priority, while each handler
+/// runs only the interrupts with a higher priority are enabled.
+///
+/// Everything on this path is inlined or in IRAM, it runs while the flash cache is disabled for
+/// interrupts marked with `set_iram_safe`. It only uses plain `while` loops, iterators are
+/// separate calls into flash without optimizations.
 #[no_mangle]
 #[link_section = ".rwtext"]
-extern "C" fn __interrupt_trampoline(mask: u32, context: ExceptionContext) {
-    if InterruptType::SLC.mask() & mask > 0 {
-        InterruptType::SLC.call(&context);
-    };
-    if InterruptType::SPI.mask() & mask > 0 {
-        InterruptType::SPI.call(&context);
-    };
-    if InterruptType::GPIO.mask() & mask > 0 {
-        InterruptType::GPIO.call(&context);
-    };
-    if InterruptType::UART.mask() & mask > 0 {
-        InterruptType::UART.call(&context);
-    };
-    if InterruptType::CCOMPARE.mask() & mask > 0 {
-        InterruptType::CCOMPARE.call(&context);
-    };
-    if InterruptType::SOFT.mask() & mask > 0 {
-        InterruptType::SOFT.call(&context);
-    };
-    if InterruptType::WDT.mask() & mask > 0 {
-        InterruptType::WDT.call(&context);
-    };
-    if InterruptType::TIMER1.mask() & mask > 0 {
-        InterruptType::TIMER1.call(&context);
-    };
+extern "C" fn __interrupt_trampoline(mask: u32, context: &mut ExceptionContext) {
+    unsafe {
+        let previous = CURRENT_PRIORITY;
+
+        let mut priority = PRIORITY_LEVELS - 1;
+        while priority > previous as usize {
+            let lines = mask & PRIORITY_LINES[priority];
+            if lines != 0 {
+                CURRENT_PRIORITY = priority as u8;
+                write_intenable(ENABLED_LINES & lines_above(priority as u8));
+                let ps = set_intlevel_0();
+
+                call_pending(lines, context);
+
+                restore_ps(ps);
+            }
+            priority -= 1;
+        }
+
+        CURRENT_PRIORITY = previous;
+        context.set_intenable(ENABLED_LINES & lines_above(previous));
+    }
 }
 
-pub fn enable_interrupt(ty: InterruptType) -> u32 {
-    let type_mask = ty.mask();
-    let mask: u32;
-    unsafe {
-        llvm_asm!("
-            rsil a7, 2
-            rsr.intenable a4
-            or a4, a4, a5
-            wsr.intenable a4
-            wsr.ps a7
-            rsync
-            " :"={a4}"(mask): "{a5}"(type_mask) : "a7" : "volatile"
-        );
+#[inline(always)]
+fn call_pending(mask: u32, context: &mut ExceptionContext) {
+    let mut line = 0;
+    while line < INTERRUPT_LINES {
+        if mask & (1 << line) > 0 {
+            call(line, context);
+        }
+        line += 1;
+    }
+}
+
+/// Interrupt lines with a priority above `priority`
+#[inline(always)]
+unsafe fn lines_above(priority: u8) -> u32 {
+    let mut mask = 0;
+    let mut level = priority as usize + 1;
+    while level < PRIORITY_LEVELS {
+        mask |= PRIORITY_LINES[level];
+        level += 1;
     }
     mask
 }
 
+#[inline(always)]
+unsafe fn write_intenable(mask: u32) {
+    llvm_asm!("wsr.intenable $0; rsync" :: "r"(mask) :: "volatile");
+}
+
+/// Mask all level 1 interrupts, returns the previous PS
+#[inline(always)]
+pub(crate) unsafe fn set_intlevel_2() -> u32 {
+    let ps: u32;
+    llvm_asm!("rsil $0, 2" : "=r"(ps) ::: "volatile");
+    ps
+}
+
+/// Unmask all interrupts, returns the previous PS
+#[inline(always)]
+unsafe fn set_intlevel_0() -> u32 {
+    let ps: u32;
+    llvm_asm!("rsil $0, 0" : "=r"(ps) ::: "volatile");
+    ps
+}
+
+#[inline(always)]
+pub(crate) unsafe fn restore_ps(ps: u32) {
+    llvm_asm!("wsr.ps $0; rsync" :: "r"(ps) : "memory" : "volatile");
+}
+
+/// Whether the interrupted code runs outside of interrupt handlers and `mask_up_to`
+#[inline(always)]
+pub(crate) fn at_base_priority() -> bool {
+    unsafe { CURRENT_PRIORITY == 0 }
+}
+
+/// Set the priority of an interrupt
+pub fn set_priority(ty: InterruptType, priority: Priority) {
+    unsafe {
+        let ps = set_intlevel_2();
+        for lines in PRIORITY_LINES.iter_mut() {
+            *lines &= !ty.mask();
+        }
+        PRIORITY_LINES[priority as usize] |= ty.mask();
+        write_intenable(ENABLED_LINES & lines_above(CURRENT_PRIORITY));
+        restore_ps(ps);
+    }
+}
+
+/// Get the priority of an interrupt
+pub fn priority(ty: InterruptType) -> Priority {
+    unsafe {
+        let level = PRIORITY_LINES
+            .iter()
+            .position(|lines| lines & ty.mask() > 0)
+            .unwrap_or(Priority::MIN as usize);
+        Priority::from_u8(level as u8)
+    }
+}
+
+/// Execute closure `f` with all interrupts up to and including `priority` masked
+///
+/// Interrupts with a higher priority can still preempt the closure.
+pub fn mask_up_to<F, R>(priority: Priority, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    unsafe {
+        let ps = set_intlevel_2();
+        let previous = CURRENT_PRIORITY;
+        if priority as u8 > previous {
+            CURRENT_PRIORITY = priority as u8;
+            write_intenable(ENABLED_LINES & lines_above(priority as u8));
+        }
+        restore_ps(ps);
+
+        let result = f();
+
+        let ps = set_intlevel_2();
+        CURRENT_PRIORITY = previous;
+        write_intenable(ENABLED_LINES & lines_above(previous));
+        restore_ps(ps);
+
+        result
+    }
+}
+
+/// Execute closure `f` in an interrupt-free context
+///
+/// The `CriticalSection` token can be used to access data in a `Mutex`, which is the way to share
+/// state with `#[interrupt]` handlers:
+///
+/// ```ignore
+/// use core::cell::RefCell;
+/// use xtensa_lx106_rt::interrupt::{self, Mutex};
+///
+/// static COUNTER: Mutex<RefCell<u32>> = Mutex::new(RefCell::new(0));
+///
+/// interrupt::free(|cs| *COUNTER.borrow(cs).borrow_mut() += 1);
+/// ```
+pub fn free<F, R>(f: F) -> R
+where
+    F: FnOnce(CriticalSection) -> R,
+{
+    unsafe {
+        let ps = set_intlevel_2();
+        let result = f(CriticalSection::new());
+        restore_ps(ps);
+        result
+    }
+}
+
+#[cfg(feature = "critical-section-impl")]
+struct LevelOneCriticalSection;
+
+#[cfg(feature = "critical-section-impl")]
+critical_section::set_impl!(LevelOneCriticalSection);
+
+#[cfg(feature = "critical-section-impl")]
+unsafe impl critical_section::Impl for LevelOneCriticalSection {
+    unsafe fn acquire() -> critical_section::RawRestoreState {
+        set_intlevel_2()
+    }
+
+    unsafe fn release(ps: critical_section::RawRestoreState) {
+        restore_ps(ps)
+    }
+}
+
+/// Register a handler for an interrupt at runtime, replacing any previously registered handler
+///
+/// The handler is called with `ctx` instead of the handler bound at link time with
+/// `#[interrupt]`, similar to `ets_isr_attach` in the ROM. Like any interrupt handler it should
+/// be placed in IRAM with `#[ram]`.
+pub fn register<T: Sync>(
+    ty: InterruptType,
+    handler: fn(&'static T, &mut ExceptionContext),
+    ctx: &'static T,
+) {
+    replace(ty, handler, ctx);
+}
+
+/// Remove the handler registered at runtime for an interrupt
+///
+/// The interrupt is handled by the handler bound at link time again.
+pub fn unregister(ty: InterruptType) {
+    restore(ty, Registration { handler: 0, ctx: 0 });
+}
+
+/// Handler and context registered at runtime for an interrupt, `handler` is 0 without one
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Registration {
+    handler: usize,
+    ctx: usize,
+}
+
+/// Register a handler like `register`, returns the registration it replaced
+pub(crate) fn replace<T: Sync>(
+    ty: InterruptType,
+    handler: fn(&'static T, &mut ExceptionContext),
+    ctx: &'static T,
+) -> Registration {
+    restore(
+        ty,
+        Registration {
+            handler: handler as usize,
+            ctx: ctx as *const T as usize,
+        },
+    )
+}
+
+/// Put back a registration returned by `replace`, returns the registration it replaced
+pub(crate) fn restore(ty: InterruptType, registration: Registration) -> Registration {
+    let line = ty as usize;
+    unsafe {
+        let ps = set_intlevel_2();
+        let previous = Registration {
+            handler: read_volatile(&RUNTIME_HANDLERS[line]),
+            ctx: read_volatile(&RUNTIME_CONTEXTS[line]),
+        };
+        write_volatile(&mut RUNTIME_CONTEXTS[line], registration.ctx);
+        write_volatile(&mut RUNTIME_HANDLERS[line], registration.handler);
+        restore_ps(ps);
+        previous
+    }
+}
+
+/// Take over the SOFT interrupt, which the runtime raises with `raise_soft` to end `waiti` or
+/// switch threads
+pub(crate) fn install_soft_handler() {
+    register(InterruptType::SOFT, on_soft, &());
+    enable_interrupt(InterruptType::SOFT);
+}
+
+/// Raise the SOFT interrupt
+#[inline(always)]
+pub(crate) fn raise_soft() {
+    unsafe {
+        llvm_asm!("wsr.intset $0; rsync" :: "r"(InterruptType::SOFT.mask()) :: "volatile");
+    }
+}
+
+/// Only clears the SOFT interrupt, taking it ends `waiti` and gives the switch hook a chance to run
+#[link_section = ".rwtext"]
+fn on_soft(_: &'static (), _: &mut ExceptionContext) {
+    unsafe {
+        llvm_asm!("wsr.intclear $0; rsync" :: "r"(InterruptType::SOFT.mask()) :: "volatile");
+    }
+}
+
+/// Interrupt lines marked with `set_iram_safe`
+static mut IRAM_SAFE_LINES: u32 = 0;
+
+/// Mark whether the handlers of an interrupt only use code and data in RAM
+///
+/// Interrupts which aren't marked are disabled while `flash_cache` has the flash cache disabled.
+/// Handlers of marked interrupts must not call into flash, including compiler intrinsics like
+/// `__udivdi3`, or read constants placed in flash.
+pub fn set_iram_safe(ty: InterruptType, safe: bool) {
+    free(|_| unsafe {
+        if safe {
+            IRAM_SAFE_LINES |= ty.mask();
+        } else {
+            IRAM_SAFE_LINES &= !ty.mask();
+        }
+    })
+}
+
+/// Interrupt lines which can't run while the flash cache is disabled
+pub(crate) fn lines_in_flash() -> u32 {
+    unsafe { !read_volatile(&IRAM_SAFE_LINES) }
+}
+
+/// Disable the interrupt lines in `mask`, returns the lines to pass to `resume_lines`
+///
+/// Pending interrupts on these lines are handled once they are resumed.
+#[inline(always)]
+pub(crate) unsafe fn suspend_lines(mask: u32) -> u32 {
+    let ps = set_intlevel_2();
+    let suspended = ENABLED_LINES & mask;
+    ENABLED_LINES &= !mask;
+    write_intenable(ENABLED_LINES & lines_above(CURRENT_PRIORITY));
+    restore_ps(ps);
+    suspended
+}
+
+/// Enable the interrupt lines disabled by `suspend_lines` again
+#[inline(always)]
+pub(crate) unsafe fn resume_lines(suspended: u32) {
+    let ps = set_intlevel_2();
+    ENABLED_LINES |= suspended;
+    write_intenable(ENABLED_LINES & lines_above(CURRENT_PRIORITY));
+    restore_ps(ps);
+}
+
+/// Enable an interrupt, returns the new value of `INTENABLE`
+///
+/// The interrupt is only unmasked in `INTENABLE` while no interrupt handler or critical section
+/// with the same or a higher priority is running.
+pub fn enable_interrupt(ty: InterruptType) -> u32 {
+    unsafe {
+        let ps = set_intlevel_2();
+        ENABLED_LINES |= ty.mask();
+        let mask = ENABLED_LINES & lines_above(CURRENT_PRIORITY);
+        write_intenable(mask);
+        restore_ps(ps);
+        mask
+    }
+}
+
+/// Disable an interrupt, returns the new value of `INTENABLE`
 pub fn disable_interrupt(ty: InterruptType) -> u32 {
-    let type_mask = !(1u32 << ty as u8);
-    let mask: u32;
-    unsafe {
-        llvm_asm!("
-            rsil a7, 2
-            rsr.intenable a4
-            and a4, a4, a5
-            wsr.intenable a4
-            wsr.ps a7
-            rsync
-            " :"={a4}"(mask): "{a5}"(type_mask) : "a7" : "volatile"
-        );
+    unsafe {
+        let ps = set_intlevel_2();
+        ENABLED_LINES &= !ty.mask();
+        let mask = ENABLED_LINES & lines_above(CURRENT_PRIORITY);
+        write_intenable(mask);
+        restore_ps(ps);
+        mask
+    }
+}
+
+/// DPORT register selecting whether the FRC1 timer interrupt is routed to the NMI
+const DPORT_NMI_ROUTE: *mut u32 = 0x3ff0_0000 as *mut u32;
+const DPORT_NMI_ROUTE_TIMER1: u32 = 1;
+
+/// Route the FRC1 timer interrupt to the NMI instead of `InterruptType::TIMER1`
+///
+/// While routed to the NMI the timer interrupt is handled by the `#[nmi]` handler, which can
+/// interrupt level 1 interrupt and exception handlers.
+pub fn route_timer1_to_nmi() {
+    unsafe {
+        let value = read_volatile(DPORT_NMI_ROUTE);
+        write_volatile(DPORT_NMI_ROUTE, value | DPORT_NMI_ROUTE_TIMER1);
+    }
+}
+
+/// Route the FRC1 timer interrupt back to `InterruptType::TIMER1`
+pub fn route_timer1_to_interrupt() {
+    unsafe {
+        let value = read_volatile(DPORT_NMI_ROUTE);
+        write_volatile(DPORT_NMI_ROUTE, value & !DPORT_NMI_ROUTE_TIMER1);
     }
-    mask
 }
 
 pub fn timer0_read() -> u32 {
@@ -5,57 +5,70 @@
 
 use r0;
 
-pub use xtensa_lx106_rt_proc_macros::{entry, pre_init, exception, interrupt};
+pub use xtensa_lx106_rt_proc_macros::{
+    debug_exception, double_exception, entry, exception, interrupt, kernel_exception, nmi, pre_init,
+    ram,
+};
 pub use crate::exception::{ExceptionCause, ExceptionContext};
-use core::ptr::{write_volatile, read_volatile};
 
+pub mod alarm;
+pub mod clock;
 pub mod exception;
+#[cfg(feature = "executor")]
+pub mod executor;
+pub mod flash_cache;
+#[cfg(any(feature = "alloc", feature = "iram-heap"))]
+pub mod heap;
 pub mod interrupt;
+pub mod stack;
+pub mod thread;
+pub mod timer;
+
+/// Atomics with compare-and-swap and `fetch_*` operations, which the lx106 lacks in hardware
+///
+/// Implemented by `portable-atomic` using the `critical-section` implementation of this crate.
+#[cfg(feature = "portable-atomic")]
+pub use portable_atomic as atomic;
 
 #[doc(hidden)]
 #[no_mangle]
 pub unsafe extern "C" fn DefaultPreInit() {}
 
-const CRYSTAL_26MHZ: (u8, u8) = (136, 145);
-const CRYSTAL_40MHZ: (u8, u8) = (8, 129);
-
-pub enum CrystalFrequency {
-    Crystal26MHz,
-    Crystal40MHz,
-}
+pub use crate::clock::CrystalFrequency;
 
 /// Configure the internal PLL for a given crystal frequency
 ///
 /// Most boards use a 26MHz crystal, and the PLL will be configured for this by default.
 /// If your board uses a 40MHz crystal, you'll need to use this method to get your clock
-/// running at the expected 80MHz
+/// running at the expected 80MHz. The crystal can also be configured before `main` with
+/// `__clock_config`, see `clock`.
 pub fn set_crystal_frequency(crystal: CrystalFrequency) {
-    match crystal {
-        CrystalFrequency::Crystal26MHz => unsafe { configure_pll(CRYSTAL_26MHZ) },
-        CrystalFrequency::Crystal40MHz => unsafe { configure_pll(CRYSTAL_40MHZ) }
-    }
-}
-
-unsafe fn rom_i2c_write_reg(block: u8, host_id: u8, reg_add: u8, data: u8) {
-    let ctrl = (1 << 24) | ((data as u32) << 16) | ((reg_add as u32) << 8) | (block as u32);
-    let addr = ((host_id as u32 * 4) + 0x60000d00) as *mut u32;
-    write_volatile(addr, ctrl);
-    loop {
-        let a9 = read_volatile(addr);
-        if (a9 >> 25) & 1 == 0 {
-            return;
-        }
-    }
+    clock::configure(clock::ClockConfig {
+        crystal,
+        ..clock::config()
+    });
 }
 
-unsafe fn configure_pll((reg1, reg2): (u8, u8)) {
-    rom_i2c_write_reg(103, 4, 1, reg1);
-    rom_i2c_write_reg(103, 4, 2, reg2);
+/// Entry point, switches to the stack defined in `link.x` and enables the flash cache before
+/// running any code from flash
+#[doc(hidden)]
+#[naked]
+#[no_mangle]
+#[link_section = ".rwtext"]
+pub unsafe extern "C" fn Reset() {
+    llvm_asm!(
+        "
+        movi    a1, _stack_start          // set the stack pointer, the stack grows down
+        call0   __enable_flash_cache
+        movi    a0, __reset               // __reset is in flash, out of reach of call0
+        jx      a0
+        "
+    );
 }
 
 #[doc(hidden)]
 #[no_mangle]
-pub unsafe extern "C" fn Reset() -> ! {
+unsafe extern "C" fn __reset() -> ! {
     extern "C" {
         // These symbols come from `link.x`
         static mut _bss_start: u32;
@@ -74,9 +87,16 @@ pub unsafe extern "C" fn Reset() -> ! {
 
         // This symbol will be provided by the user via `#[pre_init]`
         fn __pre_init();
+
+        // This symbol can be provided by the user, see `clock`
+        fn __clock_config() -> clock::ClockConfig;
     }
 
-    set_crystal_frequency(CrystalFrequency::Crystal26MHz);
+    #[cfg(feature = "stack-painting")]
+    stack::paint();
+
+    let clocks = __clock_config();
+    clock::configure_hardware(clocks);
 
     __pre_init();
 
@@ -84,6 +104,14 @@ pub unsafe extern "C" fn Reset() -> ! {
     r0::zero_bss(&mut _bss_start, &mut _bss_end);
     r0::init_data(&mut _data_start, &mut _data_end, &_sidata);
 
+    stack::set_limit(stack::end());
+    clock::set_config(clocks);
+
+    #[cfg(feature = "alloc")]
+    heap::init();
+    #[cfg(feature = "iram-heap")]
+    heap::init_iram();
+
     // move vec table
     set_vecbase(&_init_start as *const u32);
 
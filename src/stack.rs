@@ -0,0 +1,91 @@
+//! Stack usage measurement and overflow detection
+//!
+//! With the `stack-painting` feature the stack is filled with a pattern at boot, the part of the
+//! stack which still holds the pattern has never been used. With the `stack-guard` feature the
+//! exception and interrupt handlers check the stack pointer of the interrupted code against the
+//! stack limit, which leaves room for their save frame above the end of the stack. On overflow the
+//! `__stack_overflow` handler is called with the save frame.
+
+use crate::exception::FRAME_SIZE;
+use crate::ExceptionContext;
+#[cfg(feature = "stack-painting")]
+use core::ptr::{read_volatile, write_volatile};
+
+/// Pattern the stack is painted with
+#[cfg(feature = "stack-painting")]
+const PAINT: u32 = 0x5afe_57ac;
+
+extern "C" {
+    // These symbols come from `link.x`
+    static _stack_start: u32;
+    static _stack_end: u32;
+}
+
+/// Lowest valid stack pointer of the running stack, checked by the `stack-guard` feature
+///
+/// One save frame above the end of the stack, so the exception handlers can push their save frame
+/// before checking it. Set by `Reset` once the RAM has been initialized, switched along with the
+/// stack by `thread`.
+#[export_name = "__stack_limit"]
+static mut STACK_LIMIT: u32 = 0;
+
+/// Highest address of the stack, the initial stack pointer
+pub fn start() -> usize {
+    unsafe { &_stack_start as *const u32 as usize }
+}
+
+/// Lowest address of the stack
+pub fn end() -> usize {
+    unsafe { &_stack_end as *const u32 as usize }
+}
+
+/// Size of the stack in bytes
+pub fn size() -> usize {
+    start() - end()
+}
+
+/// Maximum number of bytes of the stack used since boot
+///
+/// Stack used by functions which didn't write to all of their stack frame may not be counted.
+#[cfg(feature = "stack-painting")]
+pub fn high_water_mark() -> usize {
+    let mut address = end();
+    while address < start() && unsafe { read_volatile(address as *const u32) } == PAINT {
+        address += 4;
+    }
+    start() - address
+}
+
+/// Fill the unused part of the stack with `PAINT`
+///
+/// Must be inlined in `Reset`, the stack below the caller is overwritten.
+#[cfg(feature = "stack-painting")]
+#[inline(always)]
+pub(crate) unsafe fn paint() {
+    let sp: usize;
+    llvm_asm!("mov $0, a1" : "=r"(sp) ::: "volatile");
+
+    let mut address = end();
+    while address < sp {
+        write_volatile(address as *mut u32, PAINT);
+        address += 4;
+    }
+}
+
+/// Set the lowest address of the running stack, checked on exception and interrupt entry
+#[inline(always)]
+pub(crate) unsafe fn set_limit(end: usize) {
+    STACK_LIMIT = (end + FRAME_SIZE) as u32;
+}
+
+/// Get the lowest address of the running stack, as passed to `set_limit`
+#[inline(always)]
+pub(crate) unsafe fn limit() -> usize {
+    STACK_LIMIT as usize - FRAME_SIZE
+}
+
+#[no_mangle]
+#[link_section = ".rwtext"]
+extern "C" fn __default_stack_overflow(save_frame: &mut ExceptionContext) -> ! {
+    panic!("Stack overflow: {:08x?}", save_frame)
+}
@@ -0,0 +1,193 @@
+//! Preemptive threads with round-robin scheduling
+//!
+//! Threads are switched by the context switch hook when returning from a level 1 interrupt to
+//! code running outside of interrupt handlers. A switch happens when a thread yields, which raises
+//! the SOFT interrupt, or on the CCOMPARE ticks started by `start_time_slicing`. The code running
+//! from `#[entry]` is the first thread, each spawned thread runs on its own stack.
+
+use crate::alarm::{self, Alarm};
+use crate::exception::{self, FRAME_SIZE};
+use crate::interrupt;
+use crate::stack;
+use crate::timer::Instant;
+use crate::ExceptionContext;
+use core::mem::transmute;
+use core::ptr::{null_mut, read_volatile, write_volatile};
+use core::time::Duration;
+
+/// Maximum number of threads, including the `#[entry]` thread
+pub const MAX_THREADS: usize = 8;
+
+const PS_EXCM: u32 = 0x10;
+const PS_UM: u32 = 0x20;
+
+#[derive(Copy, Clone)]
+struct Slot {
+    /// Incremented every time the thread finishes, invalidating old `Thread` handles
+    generation: u32,
+    running: bool,
+    /// Saved context while the thread is switched out
+    frame: *mut ExceptionContext,
+    /// Lowest address of the thread's stack
+    stack_limit: usize,
+}
+
+static mut THREADS: [Slot; MAX_THREADS] = [Slot {
+    generation: 0,
+    running: false,
+    frame: null_mut(),
+    stack_limit: 0,
+}; MAX_THREADS];
+
+/// Index of the running thread
+static mut CURRENT: usize = 0;
+
+/// Set to switch to the next thread on return from the current interrupt
+static mut SWITCH_PENDING: bool = false;
+
+/// Whether the switch hook has been installed
+static mut INSTALLED: bool = false;
+
+/// Handle to a spawned thread
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Thread {
+    index: u8,
+    generation: u32,
+}
+
+impl Thread {
+    /// Check whether the thread has returned from its entry function
+    pub fn is_finished(self) -> bool {
+        interrupt::free(|_| unsafe {
+            let slot = &THREADS[self.index as usize];
+            slot.generation != self.generation || !slot.running
+        })
+    }
+}
+
+/// Spawn a thread running `entry` with `arg` on `stack`
+///
+/// Returns `None` if all `MAX_THREADS` threads are in use. The thread is switched to when the
+/// running threads yield or their time slice ends.
+///
+/// Panics if `stack` can't hold the initial save frame.
+pub fn spawn<T: Sync>(
+    stack: &'static mut [u32],
+    entry: fn(&'static T),
+    arg: &'static T,
+) -> Option<Thread> {
+    let bottom = stack.as_mut_ptr() as usize;
+    // the stack pointer must stay 16 byte aligned
+    let top = (bottom + stack.len() * 4) & !15;
+    assert!(
+        top >= bottom + FRAME_SIZE,
+        "thread stack too small for the save frame"
+    );
+
+    install();
+
+    interrupt::free(|_| unsafe {
+        let index = THREADS.iter().position(|slot| !slot.running)?;
+
+        // prepare the frame as if the thread was interrupted at the start of `thread_start`
+        let frame = (top - FRAME_SIZE) as *mut ExceptionContext;
+        frame.write(ExceptionContext::default());
+        let context = &mut *frame;
+        let start: extern "C" fn(usize, *const ()) -> ! = thread_start;
+        context.set_pc(start as usize as u32);
+        context.set_ps(PS_UM | PS_EXCM);
+        context.set_a(1, top as u32);
+        context.set_a(2, transmute::<fn(&'static T), usize>(entry) as u32);
+        context.set_a(3, arg as *const T as usize as u32);
+
+        let slot = &mut THREADS[index];
+        slot.running = true;
+        slot.frame = frame;
+        slot.stack_limit = bottom;
+        Some(Thread {
+            index: index as u8,
+            generation: slot.generation,
+        })
+    })
+}
+
+/// Give up the rest of the time slice to the next thread
+///
+/// The switch happens in the SOFT interrupt, when called from a critical section or interrupt
+/// handler it's delayed until it ends.
+#[link_section = ".rwtext"]
+pub fn yield_now() {
+    unsafe {
+        write_volatile(&mut SWITCH_PENDING, true);
+    }
+    interrupt::raise_soft();
+}
+
+/// Switch threads every `slice`, returns the alarm driving the switches
+///
+/// Returns `None` if the alarm queue is full. Cancelling the alarm stops time slicing, leaving
+/// switching to `yield_now`.
+pub fn start_time_slicing(slice: Duration) -> Option<Alarm> {
+    install();
+    alarm::schedule_periodic(Instant::now() + slice, slice, on_time_slice, &())
+}
+
+/// Install the switch hook and take over the SOFT interrupt
+fn install() {
+    let install = interrupt::free(|_| unsafe {
+        let install = !INSTALLED;
+        if install {
+            INSTALLED = true;
+            // the code calling `spawn` for the first time becomes the first thread
+            THREADS[CURRENT].running = true;
+            THREADS[CURRENT].stack_limit = stack::limit();
+        }
+        install
+    });
+    if install {
+        interrupt::install_soft_handler();
+        unsafe { exception::set_switch_hook(Some(switch)) };
+    }
+}
+
+/// Entry point of spawned threads, `entry` and `arg` are passed in A2 and A3 by `spawn`
+extern "C" fn thread_start(entry: usize, arg: *const ()) -> ! {
+    let entry: fn(*const ()) = unsafe { transmute(entry) };
+    entry(arg);
+
+    interrupt::free(|_| unsafe {
+        let slot = &mut THREADS[CURRENT];
+        slot.running = false;
+        slot.generation = slot.generation.wrapping_add(1);
+    });
+
+    // a finished thread is never switched to again
+    loop {
+        yield_now();
+    }
+}
+
+#[link_section = ".rwtext"]
+fn on_time_slice(_: &'static ()) {
+    unsafe { write_volatile(&mut SWITCH_PENDING, true) };
+}
+
+/// Switch hook, saves the frame of the current thread and resumes the next running thread
+#[link_section = ".rwtext"]
+fn switch(frame: &mut ExceptionContext) -> *mut ExceptionContext {
+    unsafe {
+        if !read_volatile(&SWITCH_PENDING) {
+            return frame;
+        }
+        write_volatile(&mut SWITCH_PENDING, false);
+
+        THREADS[CURRENT].frame = frame;
+        let next = (1..=MAX_THREADS)
+            .map(|offset| (CURRENT + offset) % MAX_THREADS)
+            .find(|&index| THREADS[index].running)
+            .unwrap_or(CURRENT);
+        CURRENT = next;
+        stack::set_limit(THREADS[next].stack_limit);
+        THREADS[next].frame
+    }
+}
@@ -0,0 +1,301 @@
+//! Monotonic clock, delays and a one-shot countdown based on CCOUNT and CCOMPARE0
+//!
+//! CCOUNT is extended to 64 bits by tracking its wraparound, this requires `Instant::now` to be
+//! called at least once per CCOUNT period (~53s at 80MHz). Once an alarm has been scheduled the
+//! CCOMPARE interrupt takes care of this.
+
+use crate::alarm::{self, Alarm};
+use crate::clock;
+use crate::interrupt::{self, get_cycle_count, timer0_write};
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::time::Duration;
+
+/// Frequency of the ticks counted by `Instant`, independent of the CPU clock and crystal
+pub const TICKS_PER_SECOND: u32 = 80_000_000;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Longest interval CCOMPARE0 is armed for, keeps the wraparound tracking up to date
+const MAX_ARM_CYCLES: u32 = 1 << 31;
+
+/// Shortest interval CCOMPARE0 is armed for, `arm` re-arms it if CCOUNT passed it anyway
+const MIN_ARM_CYCLES: u32 = 256;
+
+/// Ticks elapsed up to `LAST_CCOUNT`
+static mut TICKS: u64 = 0;
+
+/// CCOUNT value at the last update of `TICKS`
+static mut LAST_CCOUNT: u32 = 0;
+
+/// Fraction of a tick elapsed up to `LAST_CCOUNT`, in units of 1/`CPU_FREQUENCY` ticks
+static mut TICK_REMAINDER: u64 = 0;
+
+/// CPU frequency in Hz the CCOUNT cycles are converted with
+static mut CPU_FREQUENCY: u32 = TICKS_PER_SECOND;
+
+/// Set the CPU frequency the timers are configured for, called by `clock`
+pub(crate) fn set_cpu_frequency(hz: u32) {
+    interrupt::free(|_| {
+        // account for the cycles elapsed at the old frequency
+        Instant::now();
+        unsafe {
+            CPU_FREQUENCY = hz;
+            TICK_REMAINDER = 0;
+        }
+    });
+}
+
+/// A measurement of the monotonic clock
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Get the current time
+    #[link_section = ".rwtext"]
+    pub fn now() -> Self {
+        interrupt::free(|_| unsafe {
+            let ccount = get_cycle_count();
+            let cycles = ccount.wrapping_sub(LAST_CCOUNT) as u64;
+            let elapsed = cycles * TICKS_PER_SECOND as u64 + TICK_REMAINDER;
+            LAST_CCOUNT = ccount;
+            TICKS += elapsed / CPU_FREQUENCY as u64;
+            TICK_REMAINDER = elapsed % CPU_FREQUENCY as u64;
+            Instant(TICKS)
+        })
+    }
+
+    /// Create an instant from a number of ticks since boot
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+
+    /// Number of ticks since boot
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since `earlier`, or zero if `earlier` is later than `self`
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time elapsed since this instant
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        duration_to_ticks(duration)
+            .and_then(|ticks| self.0.checked_add(ticks))
+            .map(Instant)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        duration_to_ticks(duration)
+            .and_then(|ticks| self.0.checked_sub(ticks))
+            .map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let ticks_per_second = TICKS_PER_SECOND as u64;
+    let nanos = (ticks % ticks_per_second) * NANOS_PER_SECOND / ticks_per_second;
+    Duration::new(ticks / ticks_per_second, nanos as u32)
+}
+
+/// Convert a duration to ticks, rounding up, or `None` if it doesn't fit in 64 bits
+fn duration_to_ticks(duration: Duration) -> Option<u64> {
+    let ticks_per_second = TICKS_PER_SECOND as u64;
+    let nanos = duration.subsec_nanos() as u64;
+    duration
+        .as_secs()
+        .checked_mul(ticks_per_second)?
+        .checked_add((nanos * ticks_per_second + NANOS_PER_SECOND - 1) / NANOS_PER_SECOND)
+}
+
+/// Busy-wait for a number of CPU cycles
+#[link_section = ".rwtext"]
+fn wait_cycles(cycles: u64) {
+    let mut remaining = cycles;
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_ARM_CYCLES as u64) as u32;
+        let start = get_cycle_count();
+        while get_cycle_count().wrapping_sub(start) < chunk {}
+        remaining -= chunk as u64;
+    }
+}
+
+/// Busy-waiting delay based on CCOUNT
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Delay;
+
+impl Delay {
+    pub fn new() -> Self {
+        Delay
+    }
+}
+
+impl embedded_hal::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        let hz = clock::cpu_frequency() as u64;
+        wait_cycles((ns as u64 * hz + NANOS_PER_SECOND - 1) / NANOS_PER_SECOND);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        let hz = clock::cpu_frequency() as u64;
+        wait_cycles((us as u64 * hz + 999_999) / 1_000_000);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        let hz = clock::cpu_frequency() as u64;
+        wait_cycles((ms as u64 * hz + 999) / 1_000);
+    }
+}
+
+/// One-shot countdown, scheduled on the alarm queue
+///
+/// Any number of countdowns can be running at the same time.
+#[derive(Debug, Default)]
+pub struct CountDown {
+    deadline: Option<Instant>,
+    alarm: Option<Alarm>,
+}
+
+impl CountDown {
+    pub fn new() -> Self {
+        CountDown {
+            deadline: None,
+            alarm: None,
+        }
+    }
+
+    /// Start counting down from `duration`, cancelling the running countdown
+    ///
+    /// Returns `false` if the alarm queue is full, the countdown still expires but `wait` has to
+    /// busy-wait for it. Durations too long for the clock never expire.
+    pub fn start(&mut self, duration: Duration) -> bool {
+        self.cancel();
+        let deadline = Instant::now()
+            .checked_add(duration)
+            .unwrap_or(Instant(u64::MAX));
+        self.deadline = Some(deadline);
+        // the alarm only wakes up `wait`
+        self.alarm = alarm::schedule(deadline, |_| {}, &());
+        self.alarm.is_some()
+    }
+
+    /// Check whether the countdown has expired
+    pub fn is_expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => true,
+        }
+    }
+
+    /// Sleep until the countdown has expired
+    ///
+    /// Interrupts are enabled while sleeping, this should not be called from a critical section.
+    /// If `start` couldn't schedule an alarm this busy-waits instead.
+    pub fn wait(&self) {
+        if self.alarm.is_none() {
+            while !self.is_expired() {}
+            return;
+        }
+
+        loop {
+            unsafe {
+                let ps = interrupt::set_intlevel_2();
+                if self.is_expired() {
+                    interrupt::restore_ps(ps);
+                    return;
+                }
+                // waiti unmasks the interrupts, so the CCOMPARE interrupt can't be missed
+                llvm_asm!("waiti 0" :::: "volatile");
+                interrupt::restore_ps(ps);
+            }
+        }
+    }
+
+    /// Stop the countdown, returns `false` if it wasn't running
+    pub fn cancel(&mut self) -> bool {
+        let running = !self.is_expired();
+        if let Some(alarm) = self.alarm.take() {
+            alarm.cancel();
+        }
+        self.deadline = None;
+        running
+    }
+}
+
+impl Drop for CountDown {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Arm CCOMPARE0 for `deadline`, or for the longest interval if it's further away
+///
+/// Writing CCOMPARE0 also clears a pending CCOMPARE interrupt.
+#[link_section = ".rwtext"]
+pub(crate) fn arm(deadline: Instant) {
+    let now = Instant::now();
+    let cycles = unsafe {
+        let ticks = deadline.0.saturating_sub(now.0).min(MAX_ARM_CYCLES as u64);
+        let cycles = (ticks * CPU_FREQUENCY as u64 + TICKS_PER_SECOND as u64 - 1)
+            / TICKS_PER_SECOND as u64;
+        cycles
+            .max(MIN_ARM_CYCLES as u64)
+            .min(MAX_ARM_CYCLES as u64) as u32
+    };
+
+    // the divisions above take long, count from CCOUNT right before the write
+    let mut target = get_cycle_count().wrapping_add(cycles);
+    loop {
+        timer0_write(target);
+
+        // if CCOUNT already passed the target the interrupt would only fire after CCOUNT wraps
+        let ccount = get_cycle_count();
+        if target.wrapping_sub(ccount) as i32 > 0 {
+            break;
+        }
+        target = ccount.wrapping_add(MIN_ARM_CYCLES);
+    }
+}